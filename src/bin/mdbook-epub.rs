@@ -1,4 +1,3 @@
-extern crate env_logger;
 extern crate failure;
 extern crate mdbook;
 extern crate mdbook_epub;
@@ -6,17 +5,17 @@ extern crate pulldown_cmark;
 extern crate serde_json;
 extern crate structopt;
 
-use failure::{Error, ResultExt, SyncFailure};
+use failure::{Error, SyncFailure};
 use mdbook::renderer::RenderContext;
 use mdbook::MDBook;
 use std::env;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 use structopt::StructOpt;
 
 fn main() {
-    env_logger::init();
+    mdbook_epub::init_logger();
     let args = Args::from_args();
 
     if let Err(e) = run(&args) {
@@ -36,30 +35,128 @@ fn main() {
 }
 
 fn run(args: &Args) -> Result<(), Error> {
+    if let Some(Command::Check { json, ref root }) = args.command {
+        return check(root, json);
+    }
+
     // get a `RenderContext`, either from stdin (because we're used as a plugin)
     // or by instrumenting MDBook directly (in standalone mode).
-    let ctx: RenderContext = if args.standalone {
+    let mut ctx: RenderContext = if args.standalone {
         let md = MDBook::load(&args.root).map_err(SyncFailure::new)?;
         let destination = md.build_dir_for("epub");
 
         RenderContext::new(md.root, md.book, md.config, destination)
     } else {
-        serde_json::from_reader(io::stdin()).context("Unable to parse RenderContext")?
+        mdbook_epub::load_render_context(io::stdin(), args.force)?
     };
 
+    if args.force {
+        ctx.config
+            .set("output.epub.ignore-version-mismatch", true)
+            .map_err(SyncFailure::new)?;
+    }
+
+    if args.dry_run {
+        let report = mdbook_epub::dry_run(&ctx)?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print!("{}", report);
+        }
+        return Ok(());
+    }
+
+    if args.spine {
+        let report = mdbook_epub::spine_report(&ctx)?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print!("{}", report);
+        }
+        return Ok(());
+    }
+
+    if args.stats {
+        for (outfile, stats) in mdbook_epub::generate_with_stats(&ctx)? {
+            println!("{}:", outfile.display());
+            print!("{}", stats);
+        }
+        return Ok(());
+    }
+
     mdbook_epub::generate(&ctx)?;
 
     Ok(())
 }
 
+/// Check the book for problems, exiting with an error if there are any.
+fn check(root: &Path, json: bool) -> Result<(), Error> {
+    let md = MDBook::load(root).map_err(SyncFailure::new)?;
+    let destination = md.build_dir_for("epub");
+    let ctx = RenderContext::new(md.root, md.book, md.config, destination);
+
+    let report = mdbook_epub::check(&ctx);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
+
+    if report.has_errors() {
+        Err(failure::err_msg("The book has problems which need fixing"))
+    } else {
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, StructOpt)]
 struct Args {
+    #[structopt(subcommand)]
+    command: Option<Command>,
     #[structopt(
         short = "s",
         long = "standalone",
         help = "Run standalone (i.e. not as a mdbook plugin)"
     )]
     standalone: bool,
+    #[structopt(
+        long = "dry-run",
+        help = "List the chapters and assets which would be included without writing the book"
+    )]
+    dry_run: bool,
+    #[structopt(
+        long = "spine",
+        help = "Print the book's spine and table of contents, flagging anything suspicious"
+    )]
+    spine: bool,
+    #[structopt(
+        long = "stats",
+        help = "Print how long each phase of generating the book took, and its peak memory usage"
+    )]
+    stats: bool,
+    #[structopt(long = "json", help = "Print the dry run or spine report as JSON")]
+    json: bool,
+    #[structopt(
+        short = "f",
+        long = "force",
+        help = "Try to render the book even if mdbook's version is incompatible"
+    )]
+    force: bool,
     #[structopt(help = "The book to render.", parse(from_os_str), default_value = ".")]
     root: PathBuf,
 }
+
+#[derive(Debug, Clone, StructOpt)]
+enum Command {
+    #[structopt(
+        name = "check",
+        about = "Check the book for problems (missing assets, broken links, invalid XHTML, \
+                 missing metadata) without generating it"
+    )]
+    Check {
+        #[structopt(long = "json", help = "Print the problems as JSON")]
+        json: bool,
+        #[structopt(help = "The book to check.", parse(from_os_str), default_value = ".")]
+        root: PathBuf,
+    },
+}
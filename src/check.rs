@@ -0,0 +1,207 @@
+//! Checking a book for problems without generating it.
+
+use failure::Error;
+use mdbook::renderer::RenderContext;
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use xml::reader::EventReader;
+
+use crate::config::Config;
+use crate::generator::Generator;
+use crate::links;
+use crate::resources::{self, Asset};
+
+/// Every problem found while checking a book.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CheckReport {
+    pub problems: Vec<Problem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    /// Something which will stop the book from being generated, or make it
+    /// invalid.
+    Error,
+    /// Something which is probably a mistake, but won't break the book.
+    Warning,
+}
+
+impl CheckReport {
+    /// Go through the book looking for problems, without stopping at the
+    /// first one.
+    pub fn from_render_context(ctx: &RenderContext) -> CheckReport {
+        let mut report = CheckReport::default();
+
+        if let Err(e) = crate::version_check(ctx) {
+            report.error(describe(&e));
+        }
+
+        let assets = match resources::find_all(ctx) {
+            Ok((assets, errors)) => {
+                for e in errors {
+                    report.error(describe(&e));
+                }
+                assets
+            }
+            Err(e) => {
+                report.error(describe(&e));
+                Vec::new()
+            }
+        };
+
+        match Config::from_render_context(ctx) {
+            Ok(config) => {
+                report.check_metadata(ctx, &config);
+                report.check_files(ctx, &config);
+                report.check_chapters(ctx, &config, assets);
+            }
+            Err(e) => report.error(describe(&e)),
+        }
+
+        match links::broken_links(ctx) {
+            Ok(broken) => {
+                for link in broken {
+                    report.error(format!(
+                        "Broken link to \"{}\" in {}",
+                        link.link,
+                        link.chapter.display()
+                    ));
+                }
+            }
+            Err(e) => report.error(describe(&e)),
+        }
+
+        report
+    }
+
+    /// Did we find anything which will stop the book from being generated?
+    pub fn has_errors(&self) -> bool {
+        self.problems.iter().any(|p| p.severity == Severity::Error)
+    }
+
+    fn check_metadata(&mut self, ctx: &RenderContext, config: &Config) {
+        let book = &ctx.config.book;
+
+        if book.title.is_none() {
+            self.warning("The book doesn't have a title");
+        }
+        if book.authors.is_empty() {
+            self.warning("The book doesn't list any authors");
+        }
+        if book.language.is_none() {
+            self.warning("`book.language` isn't set, so readers will have to guess");
+        }
+        if book.description.is_none() && !config.auto_description {
+            self.warning("The book doesn't have a description");
+        }
+    }
+
+    /// Make sure every file mentioned in the config exists.
+    fn check_files(&mut self, ctx: &RenderContext, config: &Config) {
+        let mut files: Vec<&Path> = Vec::new();
+        files.extend(config.additional_css.iter().map(|p| p.as_path()));
+        files.extend(config.additional_fonts.iter().map(|p| p.as_path()));
+        files.extend(config.cover_image.as_ref().map(|p| p.as_path()));
+        files.extend(config.hyphenation_patterns.as_ref().map(|p| p.as_path()));
+        files.extend(config.pronunciation_lexicon.as_ref().map(|p| p.as_path()));
+
+        for file in files {
+            if !ctx.root.join(file).is_file() {
+                self.error(format!("{} doesn't exist", file.display()));
+            }
+        }
+    }
+
+    /// Make sure every chapter ends up as well-formed XHTML, after going
+    /// through the same post-processing and templates as in the real book.
+    fn check_chapters(&mut self, ctx: &RenderContext, config: &Config, assets: Vec<Asset>) {
+        let pages = Generator::with_config(ctx, config.clone())
+            .and_then(|mut generator| generator.rendered_chapters(assets));
+
+        match pages {
+            Ok(pages) => {
+                for (path, page) in pages {
+                    if let Some(problem) = xhtml_problem(&page) {
+                        self.error(format!("{} isn't valid XHTML, {}", path.display(), problem));
+                    }
+                }
+            }
+            Err(e) => self.error(describe(&e)),
+        }
+    }
+
+    fn error<S: Into<String>>(&mut self, message: S) {
+        self.problems.push(Problem {
+            severity: Severity::Error,
+            message: message.into(),
+        });
+    }
+
+    fn warning<S: Into<String>>(&mut self, message: S) {
+        self.problems.push(Problem {
+            severity: Severity::Warning,
+            message: message.into(),
+        });
+    }
+}
+
+/// An error message, including everything which caused it.
+fn describe(e: &Error) -> String {
+    e.iter_chain()
+        .map(|cause| cause.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// Try to parse a chapter's page as XML, returning the first error.
+fn xhtml_problem(page: &str) -> Option<String> {
+    EventReader::from_str(page)
+        .into_iter()
+        .filter_map(|event| event.err())
+        .map(|e| e.to_string())
+        .next()
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.problems.is_empty() {
+            return writeln!(f, "No problems found");
+        }
+
+        for problem in &self.problems {
+            let severity = match problem.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            writeln!(f, "{}: {}", severity, problem.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_malformed_xhtml() {
+        let page = |body: &str| {
+            format!(
+                "<html xmlns=\"http://www.w3.org/1999/xhtml\"><body>{}</body></html>",
+                body
+            )
+        };
+
+        assert_eq!(xhtml_problem(&page("<p>Hello <br /> World</p>")), None);
+        assert!(xhtml_problem(&page("<p>Hello <br> World</p>")).is_some());
+        assert!(xhtml_problem(&page("<p>Hello&nbsp;World</p>")).is_some());
+    }
+}
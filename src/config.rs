@@ -1,25 +1,237 @@
-use failure::Error;
+use chrono::{DateTime, NaiveDate};
+use failure::{self, Error};
 use mdbook::renderer::RenderContext;
-use std::path::PathBuf;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use toml::Value;
 
 /// The configuration struct used to tweak how an EPUB document is generated.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(default, rename_all = "kebab-case")]
 pub struct Config {
-    /// A list of additional stylesheets to include in the document.
+    /// A list of additional stylesheets to include in the document, relative
+    /// to the book's root.
     pub additional_css: Vec<PathBuf>,
     /// Should we use the default stylesheet (default: true)?
     pub use_default_css: bool,
+    /// Fonts to embed in the document. They are available to stylesheets as
+    /// `fonts/<filename>`.
+    pub additional_fonts: Vec<PathBuf>,
+    /// Only keep the glyphs actually used by the book when embedding fonts
+    /// (requires `pyftsubset` from fonttools).
+    pub subset_fonts: bool,
+    /// A file containing hyphenation patterns (e.g. `hyph-en-us.pat.txt`) for
+    /// the book's language. When set, soft hyphens are inserted into the
+    /// body text of every chapter.
+    pub hyphenation_patterns: Option<PathBuf>,
+    /// Write a `<book>.epub.sha256` file next to the book, containing the
+    /// checksum of every embedded file.
+    pub checksums: bool,
+    /// Try to render the book even if it was produced by an incompatible
+    /// version of `mdbook`.
+    pub ignore_version_mismatch: bool,
+    /// The book's publication date (e.g. `2019-05-01`), written as `dc:date`.
+    pub date: Option<String>,
+    /// Override the `dcterms:modified` timestamp (e.g.
+    /// `2019-05-01T00:00:00Z`) for reproducible builds. By default this comes
+    /// from `SOURCE_DATE_EPOCH` or when a chapter was last modified.
+    pub modified: Option<String>,
+    /// Use the first paragraph of the first chapter as the book's
+    /// description when `book.description` isn't set (default: true).
+    pub auto_description: bool,
+    /// The maximum length of a generated description, in characters.
+    pub auto_description_length: usize,
+    /// An image to use as the book's cover.
+    pub cover_image: Option<PathBuf>,
+    /// Settings for the generated cover, used when there's no `cover-image`.
+    pub cover: CoverConfig,
+    /// The order of the generated pages and the book's chapters in the spine.
+    /// Generated pages which aren't listed are left out, so this also
+    /// controls whether the inline table of contents (`"toc"`) is included.
+    pub spine: Vec<SpinePage>,
+    /// Put every chapter into a single XHTML document, like mdBook's print
+    /// page, instead of one document per chapter.
+    pub single_page: bool,
+    /// Chapters (by their path in `SUMMARY.md`) whose sub-chapters are left
+    /// out of the table of contents. They're still in the book, in reading
+    /// order.
+    pub collapse_toc: Vec<PathBuf>,
+    /// Only chapters nested at most this deep (1 being the top level) start
+    /// a new file. Deeper chapters are merged into their parent's file.
+    pub page_break_level: Option<usize>,
+    /// Turn chapter file names into plain ASCII (e.g. `Über uns.md` becomes
+    /// `uber-uns.html`) for readers which can't cope with anything else.
+    pub slugify_filenames: bool,
+    /// Extra transliterations used when slugifying file names (e.g.
+    /// `"ü" = "ue"`), applied before the built-in ones.
+    pub transliterations: BTreeMap<String, String>,
+    /// Replacements for the text we generate ourselves (e.g.
+    /// `table-of-contents = "Contents"`). Otherwise it's translated into
+    /// `book.language` where we know how.
+    pub labels: BTreeMap<String, String>,
+    /// Markdown text for the copyright page.
+    pub copyright: Option<String>,
+    /// Markdown text for a dedication page.
+    pub dedication: Option<String>,
+    /// Markdown text for an acknowledgments page.
+    pub acknowledgments: Option<String>,
+    /// The JPEG quality (1-100) used to recompress images. Images are left
+    /// as-is when this isn't set.
+    pub image_quality: Option<u8>,
+    /// Scale down any images wider than this many pixels.
+    pub max_image_width: Option<u32>,
+    /// What to do with links wrapped around images (default: keep them).
+    pub image_links: ImageLinks,
+    /// Show the alt text of images without a title as a caption underneath
+    /// them (default: false).
+    pub alt_captions: bool,
+    /// How many threads to load and process assets with (default: one per
+    /// CPU).
+    pub asset_workers: Option<usize>,
+    /// Per-image overrides for the image settings. The first rule whose
+    /// pattern matches an image's path (relative to `src/`) is used.
+    pub image_rules: Vec<ImageRule>,
+    /// Extra files (e.g. PDF handouts, or images only used by a template) to
+    /// embed in the book, independent of the ones found in the chapters.
+    pub additional_resources: Vec<AdditionalResource>,
+    /// A pronunciation lexicon (PLS file) for text-to-speech, relative to the
+    /// book's root.
+    pub pronunciation_lexicon: Option<PathBuf>,
+    /// How to pronounce particular words, as IPA (e.g. `mdBook = "ˌɛmˈdiːbʊk"`).
+    /// These are turned into a lexicon of their own.
+    pub pronunciations: BTreeMap<String, String>,
+    /// Copy the full build log into a file next to the EPUB (e.g.
+    /// `book.log`), so failures can be diagnosed after the fact.
+    pub log_file: bool,
+    /// Which of the web-only constructs other renderers leave behind should
+    /// be stripped from the book.
+    pub strip_web_only: WebOnly,
+    /// Basic typographic settings, turned into CSS so users don't need to
+    /// write a stylesheet of their own.
+    pub typography: Typography,
+    /// Which markdown extensions to use when rendering chapters.
+    pub markdown: MarkdownOptions,
+    /// Named variations of the book (e.g. `lite` or `eink`), each overriding
+    /// some of these settings. Every profile is generated alongside the main
+    /// book as `<book>-<profile>.epub`.
+    pub profiles: BTreeMap<String, Value>,
 }
 
 impl Config {
     /// Get the `output.epub` table from the provided `book.toml` config,
     /// falling back to the default if
     pub fn from_render_context(ctx: &RenderContext) -> Result<Config, Error> {
-        match ctx.config.get("output.epub") {
-            Some(table) => table.clone().try_into().map_err(Error::from),
-            None => Ok(Config::default()),
+        let config: Config = match ctx.config.get("output.epub") {
+            Some(table) => table.clone().try_into()?,
+            None => Config::default(),
+        };
+
+        config.inherit_html_settings(ctx).validate()
+    }
+
+    /// Get the configuration for one of the `output.epub.profiles`, which is
+    /// the `output.epub` table with the profile's settings layered on top.
+    pub fn for_profile(ctx: &RenderContext, profile: &str) -> Result<Config, Error> {
+        let mut table = match ctx.config.get("output.epub") {
+            Some(table) => table.clone(),
+            None => Value::Table(Default::default()),
+        };
+
+        let overrides = table
+            .get("profiles")
+            .and_then(|profiles| profiles.get(profile))
+            .cloned()
+            .ok_or_else(|| failure::err_msg(format!("No such profile, \"{}\"", profile)))?;
+
+        if let Value::Table(ref mut t) = table {
+            t.remove("profiles");
         }
+        merge(&mut table, overrides);
+
+        let config: Config = table.try_into()?;
+        config.inherit_html_settings(ctx).validate()
+    }
+
+    /// Reject settings which can't be turned into a sensible book.
+    fn validate(self) -> Result<Config, Error> {
+        for (i, page) in self.spine.iter().enumerate() {
+            if self.spine[..i].contains(page) {
+                let name = Value::try_from(page)
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|_| format!("{:?}", page));
+                return Err(failure::err_msg(format!(
+                    "The spine lists {} more than once",
+                    name
+                )));
+            }
+        }
+
+        for resource in &self.additional_resources {
+            if let Some(ref dest) = resource.dest {
+                let escapes = Path::new(dest).components().any(|c| match c {
+                    Component::Normal(_) | Component::CurDir => false,
+                    _ => true,
+                });
+                if escapes || dest.starts_with('/') {
+                    return Err(failure::err_msg(format!(
+                        "The destination for \"{}\" must be a relative path inside the book, \
+                         not \"{}\"",
+                        resource.src, dest
+                    )));
+                }
+            }
+        }
+
+        if let Some(ref date) = self.date {
+            if !is_iso_8601(date) {
+                return Err(failure::err_msg(format!(
+                    "The date \"{}\" isn't in ISO 8601 format (e.g. 2019-05-01)",
+                    date
+                )));
+            }
+        }
+
+        if let Some(ref modified) = self.modified {
+            if DateTime::parse_from_rfc3339(modified).is_err() {
+                return Err(failure::err_msg(format!(
+                    "The modified timestamp \"{}\" isn't in RFC 3339 format \
+                     (e.g. 2019-05-01T10:00:00Z)",
+                    modified
+                )));
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Fill in anything left unset which should match the HTML renderer.
+    fn inherit_html_settings(mut self, ctx: &RenderContext) -> Config {
+        if self.markdown.curly_quotes.is_none() {
+            self.markdown.curly_quotes = ctx
+                .config
+                .get("output.html.curly-quotes")
+                .and_then(Value::as_bool);
+        }
+
+        self
+    }
+}
+
+/// Recursively copy the values from `overrides` into `base`.
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (&mut Value::Table(ref mut base), Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
     }
 }
 
@@ -28,6 +240,402 @@ impl Default for Config {
         Config {
             use_default_css: true,
             additional_css: Vec::new(),
+            additional_fonts: Vec::new(),
+            subset_fonts: false,
+            hyphenation_patterns: None,
+            checksums: false,
+            ignore_version_mismatch: false,
+            date: None,
+            modified: None,
+            auto_description: true,
+            auto_description_length: 300,
+            cover_image: None,
+            cover: CoverConfig::default(),
+            spine: vec![SpinePage::Cover, SpinePage::Chapters],
+            single_page: false,
+            collapse_toc: Vec::new(),
+            page_break_level: None,
+            slugify_filenames: false,
+            transliterations: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            copyright: None,
+            dedication: None,
+            acknowledgments: None,
+            image_quality: None,
+            max_image_width: None,
+            image_links: ImageLinks::Keep,
+            alt_captions: false,
+            asset_workers: None,
+            image_rules: Vec::new(),
+            additional_resources: Vec::new(),
+            pronunciation_lexicon: None,
+            pronunciations: BTreeMap::new(),
+            log_file: false,
+            strip_web_only: WebOnly::default(),
+            typography: Typography::default(),
+            markdown: MarkdownOptions::default(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+/// The `[output.epub.markdown]` table.
+///
+/// By default this uses the same `pulldown-cmark` extensions as the HTML
+/// renderer, so content renders the same in both.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct MarkdownOptions {
+    pub tables: bool,
+    pub footnotes: bool,
+    pub strikethrough: bool,
+    pub tasklists: bool,
+    /// Convert straight quotes to curly quotes (default: whatever
+    /// `output.html.curly-quotes` is set to).
+    pub curly_quotes: Option<bool>,
+    /// Expand `{漢字|かんじ}` into ruby annotations (default: false).
+    pub ruby: bool,
+}
+
+impl Default for MarkdownOptions {
+    fn default() -> MarkdownOptions {
+        MarkdownOptions {
+            tables: true,
+            footnotes: true,
+            strikethrough: true,
+            tasklists: true,
+            curly_quotes: None,
+            ruby: false,
+        }
+    }
+}
+
+/// The `[output.epub.strip-web-only]` table.
+///
+/// Things which only make sense on the website sometimes end up in a book's
+/// content, and all but stray preprocessor directives are stripped by default.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct WebOnly {
+    /// Unwrap links to the HTML renderer's `print.html`, keeping their text.
+    pub print_links: bool,
+    /// Leave out the `404.md` and `print.md` chapters.
+    pub pages: bool,
+    /// Remove `{{#playground}}` and `{{#playpen}}` directives which were never
+    /// expanded.
+    pub playground: bool,
+    /// Remove any other preprocessor directives (e.g. `{{#include}}`) which
+    /// were never expanded. Off by default, because by the time we see them
+    /// the author's escaped (`\{{#include}}`) directives look just the same.
+    pub directives: bool,
+}
+
+impl Default for WebOnly {
+    fn default() -> WebOnly {
+        WebOnly {
+            print_links: true,
+            pages: true,
+            playground: true,
+            directives: false,
         }
     }
 }
+
+/// An entry in `output.epub.image-rules`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ImageRule {
+    /// A glob pattern (e.g. `"photos/*.jpg"`) matched against the image's
+    /// path relative to the `src/` directory.
+    pub pattern: String,
+    /// Override `image-quality` for these images.
+    pub quality: Option<u8>,
+    /// Override `max-image-width` for these images.
+    pub max_width: Option<u32>,
+    /// Never apply lossy compression to these images.
+    pub lossless: bool,
+    /// Embed these images exactly as they are.
+    pub keep_original: bool,
+}
+
+/// An entry in `output.epub.additional-resources`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct AdditionalResource {
+    /// The file to embed, or a glob pattern (e.g. `"handouts/*.pdf"`),
+    /// relative to the book's root directory.
+    pub src: String,
+    /// The directory inside the book to put the files in (default: the top
+    /// level).
+    pub dest: Option<String>,
+    /// The files' MIME type, when it can't be guessed from their extension.
+    pub mimetype: Option<String>,
+}
+
+/// What to do with an image which is wrapped in a link.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageLinks {
+    /// Leave the link alone.
+    Keep,
+    /// Remove the link, leaving just the image.
+    Strip,
+    /// Point the link at the embedded image, so readers can open it at full
+    /// size.
+    FullSize,
+}
+
+/// Is this a date (`2019`, `2019-05` or `2019-05-01`) or a timestamp
+/// (`2019-05-01T10:00:00Z`) in the ISO 8601 format `dc:date` expects?
+fn is_iso_8601(date: &str) -> bool {
+    lazy_static! {
+        static ref PARTIAL_DATE: Regex = Regex::new(r"^\d{4}(-(0[1-9]|1[0-2]))?$").unwrap();
+    }
+
+    PARTIAL_DATE.is_match(date)
+        || NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok()
+        || DateTime::parse_from_rfc3339(date).is_ok()
+}
+
+/// Something which can be placed in the book's spine.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpinePage {
+    /// A page showing the cover image (if there is one).
+    Cover,
+    /// A page with the book's title and authors.
+    TitlePage,
+    /// A page with the `copyright` text.
+    Copyright,
+    /// A page with the `dedication` text.
+    Dedication,
+    /// A page with the `acknowledgments` text.
+    Acknowledgments,
+    /// An inline table of contents.
+    Toc,
+    /// Every chapter from `SUMMARY.md`, except the back matter.
+    Chapters,
+    /// The suffix chapters listed after the numbered ones in `SUMMARY.md`.
+    /// These follow the chapters unless placed somewhere else.
+    BackMatter,
+}
+
+/// The `[output.epub.cover]` table.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct CoverConfig {
+    /// Generate a cover from the book's title and authors when no
+    /// `cover-image` is provided (default: false).
+    pub generate: bool,
+    /// A subtitle to show underneath the title.
+    pub subtitle: Option<String>,
+    pub background: String,
+    pub foreground: String,
+    pub font_family: String,
+}
+
+impl Default for CoverConfig {
+    fn default() -> CoverConfig {
+        CoverConfig {
+            generate: false,
+            subtitle: None,
+            background: String::from("#2b4c7e"),
+            foreground: String::from("#ffffff"),
+            font_family: String::from("serif"),
+        }
+    }
+}
+
+/// The `[output.epub.typography]` table.
+///
+/// Every setting is optional, anything left unset falls through to the
+/// default stylesheet (or whatever the reader uses).
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Typography {
+    /// How body text should be aligned (e.g. `"justify"` or `"left"`).
+    pub text_align: Option<String>,
+    /// The line height for body text (e.g. `"1.4"` or `"120%"`).
+    pub line_height: Option<String>,
+    /// The base font size (e.g. `"1em"` or `"12pt"`).
+    pub font_size: Option<String>,
+    /// Page margins, as accepted by the CSS `margin` property.
+    pub margin: Option<String>,
+    /// Should paragraphs be separated by indenting their first line or by
+    /// vertical spacing?
+    pub paragraph_style: Option<ParagraphStyle>,
+    /// The first line indent used by `paragraph-style = "indent"`.
+    pub paragraph_indent: Option<String>,
+    /// The space between paragraphs used by `paragraph-style = "block"`.
+    pub paragraph_spacing: Option<String>,
+}
+
+/// How consecutive paragraphs are set apart from each other.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ParagraphStyle {
+    /// Indent the first line, with no space between paragraphs (fiction).
+    Indent,
+    /// No indent, with a blank line between paragraphs (non-fiction).
+    Block,
+}
+
+impl Typography {
+    /// Generate the CSS corresponding to these settings.
+    pub fn stylesheet(&self) -> String {
+        let mut css = String::new();
+
+        if let Some(ref margin) = self.margin {
+            css.push_str(&format!("@page {{\n    margin: {};\n}}\n\n", margin));
+        }
+
+        let mut body = String::new();
+        if let Some(ref align) = self.text_align {
+            body.push_str(&format!("    text-align: {};\n", align));
+        }
+        if let Some(ref line_height) = self.line_height {
+            body.push_str(&format!("    line-height: {};\n", line_height));
+        }
+        if let Some(ref font_size) = self.font_size {
+            body.push_str(&format!("    font-size: {};\n", font_size));
+        }
+        if !body.is_empty() {
+            css.push_str(&format!("body {{\n{}}}\n\n", body));
+        }
+
+        match self.paragraph_style {
+            Some(ParagraphStyle::Indent) => {
+                let indent = self
+                    .paragraph_indent
+                    .as_ref()
+                    .map_or("1.25em", |s| s.as_str());
+                css.push_str(&format!(
+                    "p {{\n    text-indent: {};\n    margin: 0;\n}}\n\n",
+                    indent
+                ));
+            }
+            Some(ParagraphStyle::Block) => {
+                let spacing = self
+                    .paragraph_spacing
+                    .as_ref()
+                    .map_or("1em", |s| s.as_str());
+                css.push_str(&format!(
+                    "p {{\n    text-indent: 0;\n    margin: {} 0 0 0;\n}}\n\n",
+                    spacing
+                ));
+            }
+            None => {}
+        }
+
+        css
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_settings_override_the_base_table() {
+        let mut base: Value = toml::from_str(
+            "use-default-css = false\n[typography]\ntext-align = 'left'\nline-height = '1.4'\n",
+        )
+        .unwrap();
+        let overrides: Value = toml::from_str("[typography]\ntext-align = 'justify'\n").unwrap();
+
+        merge(&mut base, overrides);
+        let got: Config = base.try_into().unwrap();
+
+        assert!(!got.use_default_css);
+        assert_eq!(got.typography.text_align, Some(String::from("justify")));
+        assert_eq!(got.typography.line_height, Some(String::from("1.4")));
+    }
+
+    #[test]
+    fn reject_duplicate_spine_pages() {
+        let config = Config {
+            spine: vec![SpinePage::Chapters, SpinePage::Toc, SpinePage::Chapters],
+            ..Config::default()
+        };
+
+        let got = config.validate().unwrap_err();
+
+        assert_eq!(
+            got.to_string(),
+            "The spine lists \"chapters\" more than once"
+        );
+    }
+
+    #[test]
+    fn resources_must_stay_inside_the_book() {
+        let resource = |dest: &str| AdditionalResource {
+            src: String::from("handouts/*.pdf"),
+            dest: Some(dest.to_string()),
+            mimetype: None,
+        };
+
+        for dest in &["handouts", "./docs/handouts/"] {
+            let config = Config {
+                additional_resources: vec![resource(dest)],
+                ..Config::default()
+            };
+            assert!(config.validate().is_ok(), "{}", dest);
+        }
+        for dest in &["../handouts", "docs/../../handouts", "/etc"] {
+            let config = Config {
+                additional_resources: vec![resource(dest)],
+                ..Config::default()
+            };
+            assert!(config.validate().is_err(), "{}", dest);
+        }
+    }
+
+    #[test]
+    fn dates_must_be_iso_8601() {
+        for date in &["2019", "2019-05", "2019-05-01", "2019-05-01T10:00:00Z"] {
+            assert!(is_iso_8601(date), "{}", date);
+        }
+        for date in &["May 2019", "2019-13", "2019-02-30", "<b>2019</b>"] {
+            assert!(!is_iso_8601(date), "{}", date);
+        }
+
+        let config = Config {
+            date: Some(String::from("1st of May")),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn modified_timestamps_must_be_rfc_3339() {
+        let config = |modified: &str| Config {
+            modified: Some(modified.to_string()),
+            ..Config::default()
+        };
+
+        assert!(config("2019-05-01T10:00:00Z").validate().is_ok());
+        assert!(config("2019-05-01").validate().is_err());
+        assert!(config("yesterday").validate().is_err());
+    }
+
+    #[test]
+    fn unset_typography_generates_no_css() {
+        assert_eq!(Typography::default().stylesheet(), "");
+    }
+
+    #[test]
+    fn typography_settings_become_css() {
+        let typography = Typography {
+            text_align: Some(String::from("left")),
+            line_height: Some(String::from("1.4")),
+            paragraph_style: Some(ParagraphStyle::Block),
+            ..Default::default()
+        };
+
+        let css = typography.stylesheet();
+
+        assert!(css.contains("body {\n    text-align: left;\n    line-height: 1.4;\n}"));
+        assert!(css.contains("p {\n    text-indent: 0;\n    margin: 1em 0 0 0;\n}"));
+        assert!(!css.contains("@page"));
+    }
+}
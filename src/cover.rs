@@ -0,0 +1,130 @@
+//! Generating a simple cover for books which don't provide their own.
+
+use failure::Error;
+use handlebars::Handlebars;
+use serde_json::json;
+
+use crate::config::CoverConfig;
+
+/// The template used for generated covers.
+const COVER_TEMPLATE: &str = include_str!("cover.hbs");
+
+/// The longest a line on the cover may be before it's wrapped.
+const TITLE_LINE_LEN: usize = 18;
+const SUBTITLE_LINE_LEN: usize = 30;
+
+/// A page which just displays the cover image, with the (already escaped)
+/// `title`.
+pub(crate) fn cover_page(image: &str, title: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+    <head>
+        <title>{title}</title>
+        <style type="text/css">
+            body {{ margin: 0; padding: 0; text-align: center; }}
+            img {{ max-width: 100%; max-height: 100%; }}
+        </style>
+    </head>
+    <body epub:type="cover">
+        <div><img src="{image}" alt="{title}" /></div>
+    </body>
+</html>
+"#,
+        image = image,
+        title = title,
+    )
+}
+
+/// Render an SVG cover from the book's title and authors.
+pub(crate) fn generate_svg(
+    title: &str,
+    authors: &[String],
+    config: &CoverConfig,
+) -> Result<String, Error> {
+    let title_lines = wrap(title, TITLE_LINE_LEN);
+    let subtitle_lines = config
+        .subtitle
+        .as_ref()
+        .map(|s| wrap(s, SUBTITLE_LINE_LEN))
+        .unwrap_or_default();
+
+    // centre the title in the top half of the page, with the subtitle
+    // underneath
+    let mut y = 300 - (title_lines.len() as i32 - 1) * 35;
+    let mut title = Vec::new();
+    for line in title_lines {
+        title.push(json!({"y": y, "text": line}));
+        y += 70;
+    }
+
+    y += 10;
+    let mut subtitle = Vec::new();
+    for line in subtitle_lines {
+        subtitle.push(json!({"y": y, "text": line}));
+        y += 44;
+    }
+
+    let mut hbs = Handlebars::new();
+    hbs.register_template_string("cover", COVER_TEMPLATE)?;
+
+    let svg = hbs.render(
+        "cover",
+        &json!({
+            "title": title,
+            "subtitle": subtitle,
+            "authors": authors.join(", "),
+            "background": config.background,
+            "foreground": config.foreground,
+            "font_family": config.font_family,
+        }),
+    )?;
+
+    Ok(svg)
+}
+
+/// Greedily split text into lines of at most `max_len` characters (unless a
+/// single word is longer than that).
+fn wrap(text: &str, max_len: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+
+    for word in text.split_whitespace() {
+        if !current.is_empty() && current.chars().count() + word.chars().count() + 1 > max_len {
+            lines.push(current);
+            current = String::new();
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(word);
+    }
+
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_titles_are_wrapped() {
+        let got = wrap("The Rust Programming Language", TITLE_LINE_LEN);
+
+        assert_eq!(got, vec!["The Rust", "Programming", "Language"]);
+    }
+
+    #[test]
+    fn cover_text_is_escaped() {
+        let authors = vec![String::from("Steve & Carol")];
+
+        let got = generate_svg("<Title>", &authors, &CoverConfig::default()).unwrap();
+
+        assert!(got.contains("&lt;Title&gt;"));
+        assert!(got.contains("Steve &amp; Carol"));
+    }
+}
@@ -0,0 +1,418 @@
+//! Helpers for embedding fonts into the book.
+
+use failure::{self, Error, ResultExt};
+use flate2::read::ZlibDecoder;
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempdir::TempDir;
+
+use crate::links;
+
+/// The directory (relative to the `OEBPS/` root) fonts are embedded into.
+pub(crate) const FONT_DIR: &str = "fonts";
+
+/// The tool used for subsetting fonts, part of [fonttools].
+///
+/// [fonttools]: https://github.com/fonttools/fonttools
+const SUBSETTER: &str = "pyftsubset";
+
+/// The tool used for decompressing WOFF2 fonts, from [google/woff2].
+///
+/// [google/woff2]: https://github.com/google/woff2
+const WOFF2_DECOMPRESSOR: &str = "woff2_decompress";
+
+/// The container format a font file is stored in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum FontFormat {
+    /// A plain TrueType/OpenType font, which every reader understands.
+    Sfnt,
+    Woff,
+    Woff2,
+}
+
+impl FontFormat {
+    /// Sniff the format from a font's magic number.
+    pub(crate) fn detect(data: &[u8]) -> FontFormat {
+        if data.starts_with(b"wOFF") {
+            FontFormat::Woff
+        } else if data.starts_with(b"wOF2") {
+            FontFormat::Woff2
+        } else {
+            FontFormat::Sfnt
+        }
+    }
+}
+
+/// The path a font will be embedded at, relative to the `OEBPS/` root. Fonts
+/// with the same name (e.g. `a/Body.woff2` and `b/Body.ttf`) would end up in
+/// the same place, so if the name is already `taken` a hash of the font's
+/// path is appended.
+pub(crate) fn embedded_name(
+    font: &Path,
+    extension: &str,
+    taken: &HashSet<String>,
+) -> Result<String, Error> {
+    let name = font
+        .file_stem()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| failure::err_msg(format!("Invalid font path, {}", font.display())))?;
+
+    let filename = format!("{}/{}.{}", FONT_DIR, name, extension);
+    if !taken.contains(&filename) {
+        return Ok(filename);
+    }
+
+    let hash = format!("{:x}", Sha256::digest(font.to_string_lossy().as_bytes()));
+    let renamed = format!("{}/{}-{}.{}", FONT_DIR, name, &hash[..8], extension);
+    log::debug!("Renaming {} to {} to avoid a collision", filename, renamed);
+
+    Ok(renamed)
+}
+
+/// The media type a font with this extension is listed under in the manifest.
+pub(crate) fn media_type(extension: &str) -> &'static str {
+    match extension {
+        "otf" => "font/otf",
+        _ => "font/ttf",
+    }
+}
+
+/// Make sure a font is in a format readers can use, converting WOFF and WOFF2
+/// fonts to plain OTF/TTF. Returns the font and its new file extension.
+pub(crate) fn to_sfnt(data: Vec<u8>, font: &Path) -> Result<(Vec<u8>, &'static str), Error> {
+    let data = match FontFormat::detect(&data) {
+        FontFormat::Sfnt => data,
+        FontFormat::Woff => {
+            log::debug!("Converting {} from WOFF", font.display());
+            decode_woff(&data).with_context(|_| format!("Unable to convert {}", font.display()))?
+        }
+        FontFormat::Woff2 => {
+            log::debug!("Converting {} from WOFF2", font.display());
+            decode_woff2(&data, font)
+                .with_context(|_| format!("Unable to convert {}", font.display()))?
+        }
+    };
+
+    let extension = if data.starts_with(b"OTTO") {
+        "otf"
+    } else {
+        "ttf"
+    };
+
+    Ok((data, extension))
+}
+
+/// Unpack a WOFF (1.0) font back into the SFNT it was created from.
+pub(crate) fn decode_woff(data: &[u8]) -> Result<Vec<u8>, Error> {
+    const HEADER_LEN: usize = 44;
+    const WOFF_ENTRY_LEN: usize = 20;
+    const SFNT_ENTRY_LEN: usize = 16;
+
+    let flavor = read_u32(data, 4)?;
+    let num_tables = read_u16(data, 12)? as usize;
+
+    let mut tables = Vec::with_capacity(num_tables);
+    for i in 0..num_tables {
+        let entry = HEADER_LEN + i * WOFF_ENTRY_LEN;
+        let tag = read_u32(data, entry)?;
+        let offset = read_u32(data, entry + 4)? as usize;
+        let comp_length = read_u32(data, entry + 8)? as usize;
+        let orig_length = read_u32(data, entry + 12)? as usize;
+        let checksum = read_u32(data, entry + 16)?;
+
+        let raw = data
+            .get(offset..offset + comp_length)
+            .ok_or_else(|| failure::err_msg("WOFF table extends past the end of the file"))?;
+
+        let table = if comp_length < orig_length {
+            let mut table = Vec::with_capacity(orig_length);
+            ZlibDecoder::new(raw)
+                .read_to_end(&mut table)
+                .context("Unable to decompress a WOFF table")?;
+            table
+        } else {
+            raw.to_vec()
+        };
+
+        if table.len() != orig_length {
+            return Err(failure::err_msg("WOFF table has the wrong length"));
+        }
+
+        tables.push((tag, checksum, table));
+    }
+
+    let mut search_range = 1;
+    let mut entry_selector = 0;
+    while search_range * 2 <= num_tables {
+        search_range *= 2;
+        entry_selector += 1;
+    }
+    let search_range = search_range * SFNT_ENTRY_LEN;
+    let range_shift = (num_tables * SFNT_ENTRY_LEN)
+        .checked_sub(search_range)
+        .ok_or_else(|| failure::err_msg("WOFF font doesn't contain any tables"))?;
+
+    let mut sfnt = Vec::new();
+    sfnt.extend(&flavor.to_be_bytes());
+    sfnt.extend(&(num_tables as u16).to_be_bytes());
+    sfnt.extend(&(search_range as u16).to_be_bytes());
+    sfnt.extend(&(entry_selector as u16).to_be_bytes());
+    sfnt.extend(&(range_shift as u16).to_be_bytes());
+
+    let mut offset = 12 + num_tables * SFNT_ENTRY_LEN;
+    for &(tag, checksum, ref table) in &tables {
+        sfnt.extend(&tag.to_be_bytes());
+        sfnt.extend(&checksum.to_be_bytes());
+        sfnt.extend(&(offset as u32).to_be_bytes());
+        sfnt.extend(&(table.len() as u32).to_be_bytes());
+        offset += padded(table.len());
+    }
+
+    for (_, _, table) in tables {
+        let len = table.len();
+        sfnt.extend(table);
+        sfnt.resize(sfnt.len() + padded(len) - len, 0);
+    }
+
+    Ok(sfnt)
+}
+
+/// WOFF2 uses a much more involved encoding than WOFF, so we defer to the
+/// reference decoder.
+fn decode_woff2(data: &[u8], font: &Path) -> Result<Vec<u8>, Error> {
+    let scratch = scratch_dir()?;
+    let input = scratch
+        .path()
+        .join(scratch_name(font))
+        .with_extension("woff2");
+    // the decompressed font is written next to the input
+    let output = input.with_extension("ttf");
+    fs::write(&input, data).context("Unable to write the WOFF2 font")?;
+
+    let status = Command::new(WOFF2_DECOMPRESSOR).arg(&input).status();
+    if let Err(ref e) = status {
+        if e.kind() == io::ErrorKind::NotFound {
+            return Err(failure::err_msg(format!(
+                "Converting WOFF2 fonts needs {}, which isn't installed (or isn't on the PATH)",
+                WOFF2_DECOMPRESSOR
+            )));
+        }
+    }
+    let status = status.with_context(|_| format!("Unable to run {}", WOFF2_DECOMPRESSOR))?;
+
+    if !status.success() {
+        return Err(failure::err_msg(format!("{} failed", WOFF2_DECOMPRESSOR)));
+    }
+
+    let sfnt = fs::read(&output).context("Unable to read the decompressed font")?;
+
+    Ok(sfnt)
+}
+
+/// Find any WOFF/WOFF2 fonts on disk referenced by a stylesheet's `url()`s.
+pub(crate) fn woff_fonts_in_css(css: &str, css_dir: &Path) -> Vec<PathBuf> {
+    CSS_URL
+        .captures_iter(css)
+        .map(|caps| caps[1].to_string())
+        .filter(|url| url.ends_with(".woff") || url.ends_with(".woff2"))
+        .filter(|url| !url.contains("://"))
+        .map(|url| css_dir.join(url))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Point every `url()` which refers to one of the `embedded` fonts (keyed by
+/// file name) at the font's location inside the book. Stylesheets are embedded
+/// `to_root` away from the `OEBPS/` root (e.g. `../`), so that prefix is added
+/// to the fonts and to any other relative `url()`.
+pub(crate) fn rewrite_css_urls(
+    css: &str,
+    embedded: &HashMap<String, String>,
+    to_root: &str,
+) -> String {
+    CSS_URL
+        .replace_all(css, |caps: &Captures<'_>| {
+            let url = &caps[1];
+            let file_name = url.rsplit('/').next().unwrap_or_default();
+            match embedded.get(file_name) {
+                Some(path) => format!("url(\"{}{}\")", to_root, path),
+                None if links::is_relative(url) && !url.starts_with("data:") => {
+                    format!("url(\"{}{}\")", to_root, url)
+                }
+                None => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+lazy_static! {
+    static ref CSS_URL: Regex = Regex::new(r#"url\(\s*['"]?([^'")\s]+)['"]?\s*\)"#).unwrap();
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, Error> {
+    match data.get(offset..offset + 2) {
+        Some(b) => Ok(u16::from(b[0]) << 8 | u16::from(b[1])),
+        None => Err(failure::err_msg("Unexpected end of font file")),
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Error> {
+    Ok(u32::from(read_u16(data, offset)?) << 16 | u32::from(read_u16(data, offset + 2)?))
+}
+
+/// Shrink a font down to only the glyphs needed to display `used`.
+pub(crate) fn subset(font: &Path, used: &BTreeSet<char>) -> Result<Vec<u8>, Error> {
+    let scratch = scratch_dir()?;
+    let code_points = scratch.path().join("unicodes.txt");
+    let output = scratch.path().join(scratch_name(font));
+
+    fs::write(&code_points, unicodes(used)).context("Unable to write the code point list")?;
+
+    let status = Command::new(SUBSETTER)
+        .arg(font)
+        .arg(format!("--unicodes-file={}", code_points.display()))
+        .arg(format!("--output-file={}", output.display()))
+        .arg("--layout-features=*")
+        .arg("--notdef-outline")
+        .status()
+        .with_context(|_| format!("Unable to run {}", SUBSETTER))?;
+
+    if !status.success() {
+        return Err(failure::err_msg(format!(
+            "{} failed to subset {}",
+            SUBSETTER,
+            font.display()
+        )));
+    }
+
+    let subset = fs::read(&output).context("Unable to read the subset font")?;
+
+    Ok(subset)
+}
+
+/// The code points to keep when subsetting, in the format `pyftsubset`'s
+/// `--unicodes-file` expects.
+fn unicodes(used: &BTreeSet<char>) -> String {
+    let unicodes: Vec<String> = used
+        .iter()
+        .map(|&c| format!("U+{:04X}", c as u32))
+        .collect();
+    unicodes.join("\n")
+}
+
+/// A private directory for the files passed to and from external tools,
+/// which is removed (along with everything in it) when it's dropped.
+fn scratch_dir() -> Result<TempDir, Error> {
+    let dir = TempDir::new("mdbook-epub").context("Unable to create a temporary directory")?;
+    Ok(dir)
+}
+
+/// The name a font's scratch copy is given.
+fn scratch_name(font: &Path) -> &str {
+    font.file_name().and_then(|n| n.to_str()).unwrap_or("font")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpack_an_uncompressed_woff() {
+        let mut woff = Vec::new();
+        woff.extend(b"wOFF");
+        woff.extend(&0x0001_0000_u32.to_be_bytes()); // flavor
+        woff.extend(&72_u32.to_be_bytes()); // length
+        woff.extend(&1_u16.to_be_bytes()); // numTables
+        woff.resize(44, 0);
+        woff.extend(b"test");
+        woff.extend(&64_u32.to_be_bytes()); // offset
+        woff.extend(&5_u32.to_be_bytes()); // compLength
+        woff.extend(&5_u32.to_be_bytes()); // origLength
+        woff.extend(&42_u32.to_be_bytes()); // origChecksum
+        woff.extend(b"hello\0\0\0");
+
+        let got = decode_woff(&woff).unwrap();
+
+        let mut should_be = Vec::new();
+        should_be.extend(&0x0001_0000_u32.to_be_bytes());
+        should_be.extend(&[0, 1, 0, 16, 0, 0, 0, 0]);
+        should_be.extend(b"test");
+        should_be.extend(&42_u32.to_be_bytes());
+        should_be.extend(&28_u32.to_be_bytes());
+        should_be.extend(&5_u32.to_be_bytes());
+        should_be.extend(b"hello\0\0\0");
+        assert_eq!(got, should_be);
+        assert_eq!(FontFormat::detect(&woff), FontFormat::Woff);
+        assert_eq!(FontFormat::detect(&got), FontFormat::Sfnt);
+    }
+
+    #[test]
+    fn keep_every_used_code_point_when_subsetting() {
+        let used: BTreeSet<char> = "a\u{e9}\u{4e2d}\u{1f600}".chars().collect();
+
+        let got = unicodes(&used);
+
+        assert_eq!(got, "U+0061\nU+00E9\nU+4E2D\nU+1F600");
+    }
+
+    #[test]
+    fn fonts_are_embedded_under_the_font_dir() {
+        let font = Path::new("static/fonts/MyFont.woff2");
+        let mut taken = HashSet::new();
+
+        assert_eq!(
+            embedded_name(font, "ttf", &taken).unwrap(),
+            "fonts/MyFont.ttf"
+        );
+
+        taken.insert(String::from("fonts/MyFont.ttf"));
+        let other = embedded_name(Path::new("other/MyFont.ttf"), "ttf", &taken).unwrap();
+        assert!(other.starts_with("fonts/MyFont-") && other.ends_with(".ttf"));
+        assert_eq!(other.len(), "fonts/MyFont-12345678.ttf".len());
+        assert_eq!(media_type("ttf"), "font/ttf");
+        assert_eq!(media_type("otf"), "font/otf");
+    }
+
+    #[test]
+    fn reject_a_woff_without_tables() {
+        let mut woff = Vec::new();
+        woff.extend(b"wOFF");
+        woff.extend(&0x0001_0000_u32.to_be_bytes()); // flavor
+        woff.extend(&44_u32.to_be_bytes()); // length
+        woff.extend(&0_u16.to_be_bytes()); // numTables
+        woff.resize(44, 0);
+
+        let got = decode_woff(&woff);
+
+        assert!(got.is_err());
+    }
+
+    #[test]
+    fn point_css_urls_at_embedded_fonts() {
+        let mut embedded = HashMap::new();
+        embedded.insert(String::from("a.woff"), String::from("fonts/a.ttf"));
+        let src = concat!(
+            "src: url('../static/a.woff') format('woff'), url(b.woff2);\n",
+            "background: url(images/bg.png), url(https://example.com/x.png), ",
+            "url(data:image/png;base64,AAAA);",
+        );
+        let should_be = concat!(
+            "src: url(\"../fonts/a.ttf\") format('woff'), url(\"../b.woff2\");\n",
+            "background: url(\"../images/bg.png\"), url(https://example.com/x.png), ",
+            "url(data:image/png;base64,AAAA);",
+        );
+
+        let got = rewrite_css_urls(src, &embedded, "../");
+
+        assert_eq!(got, should_be);
+    }
+}
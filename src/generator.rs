@@ -1,22 +1,521 @@
-use std::fs::File;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::{Cursor, Read, Write};
 
-use epub_builder::{EpubBuilder, EpubContent, ZipLibrary};
+use chrono::{DateTime, TimeZone, Utc};
+use epub_builder::{EpubBuilder, EpubContent, ReferenceType, TocElement, ZipLibrary};
 use failure::{Error, ResultExt};
 use handlebars::Handlebars;
 use mdbook::book::{BookItem, Chapter};
 use mdbook::renderer::RenderContext;
 use mdbook::theme::Theme;
-use regex::Regex;
+use regex::{Captures, Regex};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Instant, SystemTime};
 
-use crate::config::Config;
+use crate::config::{Config, ImageLinks, MarkdownOptions, SpinePage};
+use crate::cover;
+use crate::fonts;
+use crate::hyphenation::Hyphenator;
+use crate::i18n::{self, Labels};
+use crate::images::{self, ImageSettings};
+use crate::lexicon;
+use crate::links;
+use crate::logging;
+use crate::markdown;
+use crate::package::{self, Landmark};
+use crate::report::{SpineReport, TocEntry};
 use crate::resources::{self, Asset};
-use crate::utils::ResultExt as _;
+use crate::slug;
+use crate::stats::Stats;
+use crate::utils::{self, ResultExt as _};
+use crate::web_only;
 use crate::DEFAULT_CSS;
 
+/// Partials rendered at the top and bottom of every chapter. They're empty
+/// unless a theme provides its own `header.hbs` or `footer.hbs`.
+const CHAPTER_PARTIALS: &[&str] = &["header", "footer"];
+
+/// The templates used for generated pages containing text from the config,
+/// and their defaults. A theme can override them by providing its own
+/// `<name>.hbs`.
+const FRONT_MATTER_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "copyright",
+        "<section epub:type=\"copyright-page\" class=\"copyright\">\n{{{content}}}</section>\n",
+    ),
+    (
+        "dedication",
+        "<section epub:type=\"dedication\" class=\"dedication\">\n{{{content}}}</section>\n",
+    ),
+    (
+        "acknowledgments",
+        "<section epub:type=\"acknowledgments\" class=\"acknowledgments\">\n\
+         <h1>{{title}}</h1>\n{{{content}}}</section>\n",
+    ),
+];
+
+/// Where the default stylesheet is embedded.
+const DEFAULT_STYLESHEET: &str = "stylesheet.css";
+
+/// The paths (relative to the `OEBPS/` root) of every stylesheet which will be
+/// embedded, in cascade order.
+fn stylesheet_names(config: &Config) -> Vec<String> {
+    let mut names = Vec::new();
+
+    if config.use_default_css {
+        names.push(DEFAULT_STYLESHEET.to_string());
+    }
+    if !config.typography.stylesheet().is_empty() {
+        names.push(String::from("styles/typography.css"));
+    }
+
+    for (i, additional_css) in config.additional_css.iter().enumerate() {
+        let file_name = additional_css
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("style.css");
+
+        let mut name = format!("styles/{}", file_name);
+        if names.contains(&name) {
+            name = format!("styles/{}-{}", i, file_name);
+        }
+        names.push(name);
+    }
+
+    names
+}
+
+/// The file `epub-builder` puts the inline table of contents in.
+const INLINE_TOC: &str = "toc.xhtml";
+
+/// The files `epub-builder` generates itself. It always writes a
+/// `stylesheet.css`, even an empty one when the default CSS is turned off.
+const GENERATED_FILES: &[&str] = &[
+    "content.opf",
+    "toc.ncx",
+    "nav.xhtml",
+    INLINE_TOC,
+    DEFAULT_STYLESHEET,
+];
+
+/// The document every chapter is put into in single page mode.
+const SINGLE_PAGE: &str = "book.html";
+
+/// The path of a chapter's page, relative to the `OEBPS/` root.
+fn chapter_page(ch: &Chapter) -> String {
+    str::replace(
+        &ch.path.with_extension("html").display().to_string(),
+        "\\",
+        "/",
+    )
+}
+
+/// The id of a chapter's section when it's merged into another file.
+fn chapter_anchor(page: &str) -> String {
+    let name: String = page
+        .trim_end_matches(".html")
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect();
+
+    format!("chapter-{}", name)
+}
+
+/// A chapter's title in the TOC.
+fn toc_title(ch: &Chapter, back_matter: bool) -> String {
+    // back matter isn't numbered in the TOC
+    if back_matter {
+        ch.name.clone()
+    } else {
+        format!("{}", ch)
+    }
+}
+
+fn toc_level(ch: &Chapter) -> i32 {
+    ch.number
+        .as_ref()
+        .map(|n| n.len() as i32 - 1)
+        .unwrap_or_else(|| ch.parent_names.len() as i32)
+}
+
+/// Information about a chapter for the templates, e.g. for the header and
+/// footer partials.
+fn chapter_context(ch: &Chapter, back_matter: bool) -> serde_json::Value {
+    json!({
+        "name": ch.name,
+        "number": ch.number.as_ref().map(|n| n.to_string()),
+        "path": chapter_page(ch),
+        "parent_names": ch.parent_names,
+        "back_matter": back_matter,
+    })
+}
+
+/// Find every chapter nested inside one of the `collapsed` chapters.
+fn hidden_from_toc(sections: &[BookItem], collapsed: &[PathBuf]) -> HashSet<PathBuf> {
+    let mut hidden = HashSet::new();
+
+    for item in sections {
+        if let BookItem::Chapter(ref ch) = *item {
+            hide_sub_chapters(ch, collapsed, false, &mut hidden);
+        }
+    }
+
+    hidden
+}
+
+fn hide_sub_chapters(
+    ch: &Chapter,
+    collapsed: &[PathBuf],
+    hide: bool,
+    hidden: &mut HashSet<PathBuf>,
+) {
+    if hide {
+        hidden.insert(ch.path.clone());
+    }
+
+    let hide = hide || collapsed.contains(&ch.path);
+    for item in &ch.sub_items {
+        if let BookItem::Chapter(ref sub) = *item {
+            hide_sub_chapters(sub, collapsed, hide, hidden);
+        }
+    }
+}
+
+/// Where a chapter ends up in the book.
+#[derive(Debug, Clone, PartialEq)]
+struct Location {
+    /// The file containing the chapter, relative to the `OEBPS/` root.
+    file: String,
+    /// The id of the chapter's section, if it was merged into another file.
+    anchor: Option<String>,
+}
+
+/// Figure out which file each chapter goes into, keyed by the chapter's page.
+fn chapter_locations(sections: &[BookItem], config: &Config) -> HashMap<String, Location> {
+    let (main, back_matter) = included_chapters(sections, config);
+    let mut locations = HashMap::new();
+    let mut files = HashSet::new();
+    let mut current_file = None;
+
+    for ch in main.into_iter().chain(back_matter) {
+        let page = chapter_page(ch);
+        let depth = ch.parent_names.len() + 1;
+        let merged = config.page_break_level.map_or(false, |level| depth > level);
+        let parent = current_file.clone().filter(|_| merged);
+
+        let location = if config.single_page {
+            Location {
+                file: SINGLE_PAGE.to_string(),
+                anchor: Some(chapter_anchor(&page)),
+            }
+        } else if let Some(file) = parent {
+            Location {
+                file,
+                anchor: Some(chapter_anchor(&page)),
+            }
+        } else {
+            let name = if config.slugify_filenames {
+                slug::slugify_path(&page, &config.transliterations)
+            } else {
+                page.clone()
+            };
+
+            // different pages can slugify to the same name (e.g. "Über.md"
+            // and "uber.md")
+            let mut file = name.clone();
+            let mut n = 2;
+            while files.contains(&file.to_lowercase()) {
+                file = slug::with_suffix(&name, n);
+                n += 1;
+            }
+            files.insert(file.to_lowercase());
+
+            current_file = Some(file.clone());
+            Location { file, anchor: None }
+        };
+
+        log::trace!("{} goes into {:?}", page, location);
+        locations.insert(page, location);
+    }
+
+    locations
+}
+
+/// Make the relative links in a chapter (written relative to `chapter_dir`)
+/// work from the `file` it ends up in, pointing links to other chapters at
+/// wherever they ended up.
+fn relocate_links(
+    html: &str,
+    chapter_dir: &Path,
+    file: &str,
+    locations: &HashMap<String, Location>,
+) -> String {
+    lazy_static! {
+        static ref LINK: Regex = Regex::new(r#"\b(src|href|data)="([^"]*)""#).unwrap();
+    }
+
+    let file_dir = Path::new(file).parent().unwrap_or_else(|| Path::new(""));
+
+    LINK.replace_all(html, |caps: &Captures<'_>| {
+        let link = utils::percent_decode(&caps[2]);
+        if !links::is_relative(&link) {
+            return caps[0].to_string();
+        }
+
+        let (path, fragment) = match link.find('#') {
+            Some(i) => (&link[..i], &link[i + 1..]),
+            None => (link.as_str(), ""),
+        };
+        let target = links::normalize(&chapter_dir.join(path));
+        let target = str::replace(&target.display().to_string(), "\\", "/");
+
+        let (path, fragment) = match locations.get(&target) {
+            Some(location) => {
+                let fragment = match location.anchor {
+                    Some(ref anchor) if fragment.is_empty() => anchor.as_str(),
+                    _ => fragment,
+                };
+
+                if location.file == file && !fragment.is_empty() {
+                    (String::new(), fragment)
+                } else {
+                    (
+                        links::relative_path(file_dir, Path::new(&location.file)),
+                        fragment,
+                    )
+                }
+            }
+            // assets stay where they are
+            None if file_dir == chapter_dir => return caps[0].to_string(),
+            None => {
+                let path = links::relative_path(file_dir, Path::new(&target));
+                (path.replace(' ', "%20"), fragment)
+            }
+        };
+
+        if fragment.is_empty() {
+            format!("{}=\"{}\"", &caps[1], path)
+        } else {
+            format!("{}=\"{}#{}\"", &caps[1], path, fragment)
+        }
+    })
+    .into_owned()
+}
+
+/// Split the book's chapters into the main matter and the back matter (any
+/// unnumbered suffix chapters which follow the numbered ones in
+/// `SUMMARY.md`), flattening nested chapters.
+fn split_back_matter(sections: &[BookItem]) -> (Vec<&Chapter>, Vec<&Chapter>) {
+    let mut main = Vec::new();
+    let mut back_matter = Vec::new();
+    let mut seen_numbered = false;
+
+    for item in sections {
+        if let BookItem::Chapter(ref ch) = *item {
+            seen_numbered |= ch.number.is_some();
+
+            if seen_numbered && ch.number.is_none() {
+                flatten_chapter(ch, &mut back_matter);
+            } else {
+                flatten_chapter(ch, &mut main);
+            }
+        }
+    }
+
+    (main, back_matter)
+}
+
+/// The book's main matter and back matter, without the chapters which are
+/// left out of the EPUB.
+fn included_chapters<'b>(
+    sections: &'b [BookItem],
+    config: &Config,
+) -> (Vec<&'b Chapter>, Vec<&'b Chapter>) {
+    let (mut main, mut back_matter) = split_back_matter(sections);
+
+    if config.strip_web_only.pages {
+        main.retain(|ch| !web_only::is_web_page(ch));
+        back_matter.retain(|ch| !web_only::is_web_page(ch));
+    }
+
+    (main, back_matter)
+}
+
+fn flatten_chapter<'b>(ch: &'b Chapter, chapters: &mut Vec<&'b Chapter>) {
+    chapters.push(ch);
+
+    for item in &ch.sub_items {
+        if let BookItem::Chapter(ref sub) = *item {
+            flatten_chapter(sub, chapters);
+        }
+    }
+}
+
+/// Register the theme's page template, along with the front matter templates
+/// and chapter partials (which the theme can override).
+fn load_templates(theme_dir: &Path) -> Result<Handlebars, Error> {
+    let theme = Theme::new(theme_dir);
+
+    let mut hbs = Handlebars::new();
+    hbs.register_template_string("index", String::from_utf8(theme.index.clone())?)?;
+
+    // front matter templates can be overridden by the theme
+    for &(name, default) in FRONT_MATTER_TEMPLATES {
+        let custom = theme_dir.join(format!("{}.hbs", name));
+        let template = fs::read_to_string(&custom).unwrap_or_else(|_| default.to_string());
+        hbs.register_template_string(name, template)?;
+    }
+    for &name in CHAPTER_PARTIALS {
+        let template =
+            fs::read_to_string(theme_dir.join(format!("{}.hbs", name))).unwrap_or_default();
+        hbs.register_template_string(name, template)?;
+    }
+
+    Ok(hbs)
+}
+
+/// The order pages go in the spine. The chapters and back matter are always
+/// included, and dedication or acknowledgments pages are added in front of the
+/// chapters if their text is set but the spine doesn't mention them.
+fn spine_pages(config: &Config) -> Vec<SpinePage> {
+    let mut spine = config.spine.clone();
+    if !spine.contains(&SpinePage::Chapters) {
+        log::warn!("The spine doesn't include the chapters, adding them at the end");
+        spine.push(SpinePage::Chapters);
+    }
+
+    if !spine.contains(&SpinePage::BackMatter) {
+        let chapters = spine
+            .iter()
+            .position(|p| *p == SpinePage::Chapters)
+            .unwrap();
+        spine.insert(chapters + 1, SpinePage::BackMatter);
+    }
+
+    // if someone has written a dedication they probably want to see it
+    let chapters = spine
+        .iter()
+        .position(|p| *p == SpinePage::Chapters)
+        .unwrap();
+    if config.acknowledgments.is_some() && !spine.contains(&SpinePage::Acknowledgments) {
+        spine.insert(chapters, SpinePage::Acknowledgments);
+    }
+    if config.dedication.is_some() && !spine.contains(&SpinePage::Dedication) {
+        spine.insert(chapters, SpinePage::Dedication);
+    }
+
+    spine
+}
+
+/// The book's description, falling back to the first paragraph of the first
+/// chapter if `book.description` isn't set. It's escaped, ready to go into the
+/// package document.
+fn book_description(ctx: &RenderContext, config: &Config) -> Option<String> {
+    let desc = match ctx.config.book.description {
+        Some(ref desc) => Some(desc.clone()),
+        None if config.auto_description => ctx.book.iter().find_map(|item| match *item {
+            BookItem::Chapter(ref ch) => {
+                let content = markdown::conditional_content(&ch.content);
+                utils::first_paragraph(&content, config.auto_description_length)
+            }
+            _ => None,
+        }),
+        None => None,
+    };
+
+    desc.map(|desc| utils::escape_html(&desc))
+}
+
+/// Read a font, subset to just the `used` characters if given. Fonts which
+/// can't be subset are embedded whole.
+fn read_font(path: &Path, used: Option<&BTreeSet<char>>) -> Result<Vec<u8>, Error> {
+    if let Some(used) = used {
+        match fonts::subset(path, used) {
+            Ok(data) => return Ok(data),
+            Err(e) => log::warn!(
+                "Unable to subset {}, embedding the whole font: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    let data = fs::read(path).with_context(|_| format!("Unable to read {}", path.display()))?;
+    Ok(data)
+}
+
+/// Read and process each asset, spreading the work over a pool of `workers`
+/// threads. The results are in the same order as the `assets`.
+fn load_assets(assets: Vec<Asset>, config: &Config, workers: usize) -> Result<Vec<Vec<u8>>, Error> {
+    let workers = workers.max(1).min(assets.len());
+    log::debug!("Loading {} assets with {} workers", assets.len(), workers);
+
+    let assets = Arc::new(assets);
+    let config = Arc::new(config.clone());
+    let next = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let (assets, config) = (assets.clone(), config.clone());
+            let (next, tx) = (next.clone(), tx.clone());
+
+            thread::spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let asset = match assets.get(i) {
+                    Some(asset) => asset,
+                    None => break,
+                };
+                let _scope = logging::scope("asset", asset.filename.display());
+
+                let loaded = load_asset(asset, &config)
+                    .with_context(|_| format!("Couldn't load {}", asset.filename.display()))
+                    .map_err(Error::from);
+                if tx.send((i, loaded)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut loaded: Vec<Option<Vec<u8>>> = vec![None; assets.len()];
+    for (i, result) in rx {
+        loaded[i] = Some(result?);
+    }
+
+    for handle in handles {
+        handle
+            .join()
+            .map_err(|_| failure::err_msg("An asset worker panicked"))?;
+    }
+
+    loaded
+        .into_iter()
+        .map(|content| content.ok_or_else(|| failure::err_msg("An asset wasn't loaded")))
+        .collect()
+}
+
+fn load_asset(asset: &Asset, config: &Config) -> Result<Vec<u8>, Error> {
+    let content = fs::read(&asset.location_on_disk).context("Unable to open asset")?;
+    let settings = ImageSettings::for_image(&asset.filename, config)?;
+    images::process(content, &asset.mimetype, &settings)
+}
+
+/// Rendered markdown for each chapter, keyed by the chapter's path and the
+/// markdown options used. This lets several profiles share the rendering
+/// work.
+pub(crate) type RenderCache = Rc<RefCell<HashMap<(PathBuf, MarkdownOptions), String>>>;
+
 /// The actual EPUB book renderer.
 #[derive(Debug)]
 pub struct Generator<'a> {
@@ -24,13 +523,50 @@ pub struct Generator<'a> {
     builder: EpubBuilder<ZipLibrary>,
     config: Config,
     hbs: Handlebars,
+    default_css: String,
+    /// The cover image's path in the book, if there is one.
+    cover: Option<String>,
+    hyphenator: Option<Hyphenator>,
+    /// The generated text, in the book's language.
+    labels: Labels,
+    /// Every character used by the rendered chapters, for font subsetting.
+    used_chars: BTreeSet<char>,
+    /// Maps the file name of each embedded font to its path in the book.
+    embedded_fonts: HashMap<String, String>,
+    /// SHA-256 checksums of every file embedded in the book, keyed by their
+    /// path inside the archive.
+    checksums: BTreeMap<String, String>,
+    /// The path of every file in the book so far, relative to the `OEBPS/`
+    /// root.
+    embedded: HashSet<String>,
+    render_cache: RenderCache,
+    /// Every asset referenced by the book's chapters.
+    assets: Vec<Asset>,
+    /// The files added to the spine so far, in reading order.
+    spine: Vec<String>,
+    /// The entries added to the table of contents so far.
+    toc: Vec<TocEntry>,
+    /// The generated pages which need a title in the guide and landmarks.
+    landmarks: Vec<Landmark>,
+    /// Where each chapter ends up, keyed by the chapter's page.
+    locations: HashMap<String, Location>,
+    /// Do links need rewriting because chapters were renamed or merged?
+    relocated: bool,
+    /// The chapters inside a collapsed chapter, which aren't in the TOC.
+    hidden_from_toc: HashSet<PathBuf>,
+    stats: Stats,
 }
 
 impl<'a> Generator<'a> {
     pub fn new(ctx: &'a RenderContext) -> Result<Generator<'a>, Error> {
-        let builder = EpubBuilder::new(ZipLibrary::new().sync()?).sync()?;
-
         let config = Config::from_render_context(ctx)?;
+        Generator::with_config(ctx, config)
+    }
+
+    /// Create a generator which uses the provided config instead of the
+    /// `output.epub` table (e.g. for one of the profiles).
+    pub fn with_config(ctx: &'a RenderContext, config: Config) -> Result<Generator<'a>, Error> {
+        let builder = EpubBuilder::new(ZipLibrary::new().sync()?).sync()?;
 
         let mut theme_dir: PathBuf;
         let env_theme_dir = env::var("MDBOOKEPUB_THEME_DIR");
@@ -45,26 +581,69 @@ impl<'a> Generator<'a> {
             panic!("theme dir \"{}\" doesn't exist.", theme_dir.display());
         }
 
-        let theme = Theme::new(theme_dir);
+        // themes can replace the default stylesheet without touching the
+        // other layers
+        let default_css = match fs::read_to_string(theme_dir.join("master.css")) {
+            Ok(css) => css,
+            Err(_) => DEFAULT_CSS.to_string(),
+        };
+
+        let hbs = load_templates(&theme_dir)?;
+
+        let hyphenator = match config.hyphenation_patterns {
+            Some(ref patterns) => Some(Hyphenator::load(&ctx.root.join(patterns))?),
+            None => None,
+        };
 
-        let mut hbs = Handlebars::new();
-        hbs.register_template_string("index", String::from_utf8(theme.index.clone())?)?;
+        let language = ctx.config.book.language.as_ref().map(String::as_str);
+        let labels = Labels::new(language, &config.labels);
+
+        let hidden_from_toc = hidden_from_toc(&ctx.book.sections, &config.collapse_toc);
+        let locations = chapter_locations(&ctx.book.sections, &config);
+        let relocated = locations
+            .iter()
+            .any(|(page, location)| *page != location.file || location.anchor.is_some());
 
         Ok(Generator {
             builder,
             ctx,
             config,
-            hbs
+            hbs,
+            default_css,
+            cover: None,
+            hyphenator,
+            labels,
+            used_chars: BTreeSet::new(),
+            embedded_fonts: HashMap::new(),
+            checksums: BTreeMap::new(),
+            embedded: GENERATED_FILES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            render_cache: RenderCache::default(),
+            assets: Vec::new(),
+            spine: Vec::new(),
+            toc: Vec::new(),
+            landmarks: Vec::new(),
+            locations,
+            relocated,
+            hidden_from_toc,
+            stats: Stats::default(),
         })
     }
 
+    pub(crate) fn with_render_cache(mut self, cache: RenderCache) -> Generator<'a> {
+        self.render_cache = cache;
+        self
+    }
+
     fn populate_metadata(&mut self) -> Result<(), Error> {
         self.builder.metadata("generator", "mdbook-epub").sync()?;
 
         if let Some(title) = self.ctx.config.book.title.clone() {
             self.builder.metadata("title", title).sync()?;
         }
-        if let Some(desc) = self.ctx.config.book.description.clone() {
+        if let Some(desc) = book_description(self.ctx, &self.config) {
             self.builder.metadata("description", desc).sync()?;
         }
 
@@ -74,64 +653,705 @@ impl<'a> Generator<'a> {
                 .sync()?;
         }
 
+        // used in the nav document, toc.ncx, the guide and the inline TOC,
+        // none of which escape it
+        let toc_name = utils::escape_html(self.labels.get(i18n::TABLE_OF_CONTENTS));
+        self.builder.metadata("toc_name", toc_name).sync()?;
+
         Ok(())
     }
 
-    pub fn generate<W: Write>(mut self, writer: W) -> Result<(), Error> {
+    pub fn generate<W: Write>(&mut self, writer: W) -> Result<(), Error> {
         log::info!("Generating the EPUB book");
 
+        self.phase("prepare", Self::prepare)?;
+        self.phase("render chapters", Self::render_spine)?;
+        self.phase("embed styles", |this| {
+            this.embed_fonts()?;
+            this.embed_stylesheets()
+        })?;
+        self.phase("embed assets", |this| {
+            this.additional_assets()?;
+            this.embed_lexicons()?;
+            // these go last, so they can be checked against everything else
+            this.additional_resources()
+        })?;
+        self.phase("write zip", |this| this.write_book(writer))
+    }
+
+    /// Run one phase of generating the book, timing it and tagging everything
+    /// logged along the way with its name.
+    fn phase<F>(&mut self, name: &str, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Self) -> Result<(), Error>,
+    {
+        let _scope = logging::scope("phase", name);
+        let start = Instant::now();
+        f(self)?;
+        self.stats.record(name, start);
+
+        Ok(())
+    }
+
+    /// Find the assets, and fill in the metadata and cover image.
+    fn prepare(&mut self) -> Result<(), Error> {
+        // assets need to be found up front because their final names affect
+        // the links in each chapter
+        self.assets = resources::find(self.ctx)
+            .context("Inspecting the book for additional assets failed")?;
+
         self.populate_metadata()?;
-        self.generate_chapters()?;
+        self.add_cover_image()
+    }
+
+    /// Add each page in the spine, in order.
+    fn render_spine(&mut self) -> Result<(), Error> {
+        for page in spine_pages(&self.config) {
+            match page {
+                SpinePage::Cover => self.add_cover_page()?,
+                SpinePage::TitlePage => self.add_title_page()?,
+                SpinePage::Copyright => {
+                    let text = self.config.copyright.clone();
+                    self.add_text_page("copyright", text, ReferenceType::Copyright)?
+                }
+                SpinePage::Dedication => {
+                    let text = self.config.dedication.clone();
+                    self.add_text_page("dedication", text, ReferenceType::Dedication)?
+                }
+                SpinePage::Acknowledgments => {
+                    let text = self.config.acknowledgments.clone();
+                    self.add_text_page("acknowledgments", text, ReferenceType::Acknowledgements)?
+                }
+                SpinePage::Toc => {
+                    // the inline TOC goes wherever it is in the insertion order
+                    let title = self.labels.get(i18n::TABLE_OF_CONTENTS);
+                    self.used_chars.extend(title.chars());
+                    self.builder.inline_toc();
+                    self.spine.push(String::from(INLINE_TOC));
+                }
+                SpinePage::Chapters if self.config.single_page => self.add_single_page()?,
+                SpinePage::Chapters => self.generate_chapters(false)?,
+                // the single page already contains the back matter
+                SpinePage::BackMatter if self.config.single_page => {}
+                SpinePage::BackMatter => self.generate_chapters(true)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Generate the book and write it out.
+    fn write_book<W: Write>(&mut self, mut writer: W) -> Result<(), Error> {
+        let modified = self.modified_timestamp()?;
+        let mut epub = Vec::new();
+        self.builder.generate(&mut epub).sync()?;
+
+        // epub-builder always uses the current time and a random identifier,
+        // and writes that time as both the `dc:date` and `dcterms:modified`
+        let date = self.config.date.clone();
+        let timestamp = modified.format(package::MODIFIED_FORMAT).to_string();
+        let identifier = self.identifier();
+        // it also can't give a page a title without putting it in the TOC
+        let landmarks = &self.landmarks;
+        let files = [package::PACKAGE_DOCUMENT, package::NAVIGATION_DOCUMENT];
+        let epub = package::rewrite_files(&epub, &files, &modified, |name, text| {
+            if name == package::PACKAGE_DOCUMENT {
+                let opf = package::set_dates(&text, date.as_ref().map(String::as_str), &timestamp);
+                let opf = package::set_identifier(&opf, &identifier);
+                package::set_guide_titles(&opf, landmarks)
+            } else {
+                package::add_landmarks(&text, landmarks)
+            }
+        })?;
+        writer.write_all(&epub)?;
+
+        Ok(())
+    }
+
+    /// The book's unique identifier, which stays the same between builds.
+    fn identifier(&self) -> String {
+        let book = &self.ctx.config.book;
+        let title = book.title.as_ref().map(String::as_str).unwrap_or_default();
+        let authors = book.authors.join(", ");
+        let language = book
+            .language
+            .as_ref()
+            .map(String::as_str)
+            .unwrap_or_default();
+
+        package::identifier(&[title, &authors, language])
+    }
+
+    /// The `dcterms:modified` timestamp, taken from the config, then
+    /// `SOURCE_DATE_EPOCH`, then the most recently modified chapter.
+    fn modified_timestamp(&self) -> Result<DateTime<Utc>, Error> {
+        if let Some(ref modified) = self.config.modified {
+            let timestamp = DateTime::parse_from_rfc3339(modified)
+                .with_context(|_| format!("\"{}\" isn't a valid timestamp", modified))?;
+            return Ok(timestamp.with_timezone(&Utc));
+        }
+
+        if let Ok(epoch) = env::var("SOURCE_DATE_EPOCH") {
+            let seconds: i64 = epoch
+                .parse()
+                .with_context(|_| format!("Invalid SOURCE_DATE_EPOCH, \"{}\"", epoch))?;
+            return Utc.timestamp_opt(seconds, 0).single().ok_or_else(|| {
+                failure::err_msg(format!("SOURCE_DATE_EPOCH is out of range, \"{}\"", epoch))
+            });
+        }
+
+        let src_dir = self.ctx.root.join(&self.ctx.config.book.src);
+        let latest = self
+            .ctx
+            .book
+            .iter()
+            .filter_map(|item| match *item {
+                BookItem::Chapter(ref ch) => fs::metadata(src_dir.join(&ch.path)).ok(),
+                _ => None,
+            })
+            .filter_map(|metadata| metadata.modified().ok())
+            .max()
+            .unwrap_or_else(SystemTime::now);
+
+        Ok(DateTime::<Utc>::from(latest))
+    }
 
-        self.embed_stylesheets()?;
-        self.additional_assets()?;
-        self.builder.generate(writer).sync()?;
+    /// Add the cover image, generating one if asked to.
+    fn add_cover_image(&mut self) -> Result<(), Error> {
+        let (filename, data, mt) = match self.config.cover_image {
+            Some(ref image) => {
+                let path = self.ctx.root.join(image);
+                let data = fs::read(&path)
+                    .with_context(|_| format!("Unable to read {}", path.display()))?;
+                let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("png");
+                let mt = mime_guess::from_path(&path)
+                    .first_or_octet_stream()
+                    .to_string();
+
+                (format!("cover.{}", extension), data, mt)
+            }
+            None if self.config.cover.generate => {
+                log::debug!("Generating a cover");
+                let title = self.ctx.config.book.title.clone().unwrap_or_default();
+                let authors = &self.ctx.config.book.authors;
+                let svg = cover::generate_svg(&title, authors, &self.config.cover)
+                    .context("Unable to generate the cover")?;
+
+                (
+                    String::from("cover.svg"),
+                    svg.into_bytes(),
+                    String::from("image/svg+xml"),
+                )
+            }
+            None => return Ok(()),
+        };
+
+        log::debug!("Adding cover \"{}\"", filename);
+        self.record_file(&filename, &data);
+        self.builder
+            .add_cover_image(&filename, Cursor::new(data), mt)
+            .sync()?;
+        self.cover = Some(filename);
+
+        Ok(())
+    }
+
+    /// Add a page to show the cover on.
+    fn add_cover_page(&mut self) -> Result<(), Error> {
+        let page = match self.cover {
+            Some(ref filename) => {
+                let title = utils::escape_html(self.labels.get(i18n::COVER));
+                cover::cover_page(filename, &title)
+            }
+            None => return Ok(()),
+        };
+
+        self.record_file("cover.xhtml", page.as_bytes());
+        self.builder
+            .add_content(
+                EpubContent::new("cover.xhtml", page.as_bytes()).reftype(ReferenceType::Cover),
+            )
+            .sync()?;
+        self.spine.push(String::from("cover.xhtml"));
+        self.add_landmark("cover.xhtml", ReferenceType::Cover);
 
         Ok(())
     }
 
-    fn generate_chapters(&mut self) -> Result<(), Error> {
-        log::debug!("Rendering Chapters");
+    fn add_title_page(&mut self) -> Result<(), Error> {
+        let mut body = String::from("<section epub:type=\"titlepage\" class=\"titlepage\">\n");
+        if let Some(ref title) = self.ctx.config.book.title {
+            body.push_str(&format!(
+                "<h1 class=\"title\">{}</h1>\n",
+                utils::escape_html(title)
+            ));
+        }
+        if !self.ctx.config.book.authors.is_empty() {
+            let authors = self.ctx.config.book.authors.join(", ");
+            body.push_str(&format!(
+                "<p class=\"author\">{}</p>\n",
+                utils::escape_html(&authors)
+            ));
+        }
+        body.push_str("</section>\n");
 
-        for item in self.ctx.book.iter() {
-            if let BookItem::Chapter(ref ch) = *item {
-                // iter() gives us an iterator over every node in the tree
-                // but we only want the top level here so we can recursively
-                // visit the chapters.
-                log::debug!("Adding chapter \"{}\"", ch);
-                self.add_chapter(ch)?;
+        self.add_generated_page("titlepage.xhtml", &body, ReferenceType::TitlePage)
+    }
+
+    /// Add a page made by rendering some markdown text from the config
+    /// through one of the `FRONT_MATTER_TEMPLATES`.
+    fn add_text_page(
+        &mut self,
+        template: &str,
+        text: Option<String>,
+        reftype: ReferenceType,
+    ) -> Result<(), Error> {
+        let text = match text {
+            Some(text) => markdown::render(&text, &self.config.markdown),
+            None => {
+                log::warn!(
+                    "The spine includes a {} page but `{}` isn't set",
+                    template,
+                    template
+                );
+                return Ok(());
             }
+        };
+
+        let body = self.hbs.render(
+            template,
+            &json!({
+                "content": text,
+                "title": self.labels.get(template),
+                "labels": self.labels.template_context(),
+            }),
+        )?;
+        let path = format!("{}.xhtml", template);
+
+        self.add_generated_page(&path, &body, reftype)
+    }
+
+    /// Add one of the generated front/back matter pages. These aren't listed
+    /// in the table of contents.
+    fn add_generated_page(
+        &mut self,
+        path: &str,
+        body: &str,
+        reftype: ReferenceType,
+    ) -> Result<(), Error> {
+        log::debug!("Adding generated page \"{}\"", path);
+        // fonts need the glyphs for these pages too
+        self.used_chars.extend(body.chars());
+
+        let stylesheets = stylesheet_names(&self.config);
+        let html = self.hbs.render(
+            "index",
+            &json!({
+                "content": body,
+                "stylesheets": stylesheets,
+                "lexicons": self.lexicon_links(""),
+                "labels": self.labels.template_context(),
+            }),
+        )?;
+
+        self.record_file(path, html.as_bytes());
+        self.builder
+            .add_content(EpubContent::new(path, html.as_bytes()).reftype(reftype))
+            .sync()?;
+        self.spine.push(path.to_string());
+        self.add_landmark(path, reftype);
+
+        Ok(())
+    }
+
+    /// Give a generated page a title in the guide and landmarks, using the
+    /// same `epub:type` `epub-builder` would.
+    fn add_landmark(&mut self, href: &str, reftype: ReferenceType) {
+        let (epub_type, label) = match reftype {
+            ReferenceType::Cover => ("cover", i18n::COVER),
+            ReferenceType::TitlePage => ("titlepage", i18n::TITLE_PAGE),
+            ReferenceType::Copyright => ("copyright-page", i18n::COPYRIGHT),
+            ReferenceType::Dedication => ("dedication", i18n::DEDICATION),
+            ReferenceType::Acknowledgements => ("acknowledgements", i18n::ACKNOWLEDGMENTS),
+            _ => return,
+        };
+
+        self.landmarks.push(Landmark {
+            href: href.to_string(),
+            epub_type,
+            title: self.labels.get(label).to_string(),
+        });
+    }
+
+    fn generate_chapters(&mut self, back_matter: bool) -> Result<(), Error> {
+        let ctx = self.ctx;
+        let (main, suffix) = included_chapters(&ctx.book.sections, &self.config);
+
+        if back_matter {
+            log::debug!("Rendering Back Matter");
+        } else {
+            log::debug!("Rendering Chapters");
+        }
+
+        let chapters = if back_matter { suffix } else { main };
+
+        // chapters merged into their parent's file come straight after it
+        let mut i = 0;
+        while i < chapters.len() {
+            let merged = chapters[i + 1..]
+                .iter()
+                .take_while(|ch| self.is_merged(ch))
+                .count();
+
+            log::debug!("Adding chapter \"{}\"", chapters[i]);
+            self.add_chapter(chapters[i], &chapters[i + 1..=i + merged], back_matter)?;
+            i += merged + 1;
         }
 
         Ok(())
     }
 
-    fn add_chapter(&mut self, ch: &Chapter) -> Result<(), Error> {
-        let html = mdbook::utils::render_markdown(&ch.content, /*curly_quotes=*/false);
-        let html = self.fix_html(html);
-        let html = self.hbs.render("index", &json!({"content": html}))?;
+    /// Add a chapter, along with any of its sub-chapters which are merged into
+    /// the same file.
+    fn add_chapter(
+        &mut self,
+        ch: &Chapter,
+        merged: &[&Chapter],
+        back_matter: bool,
+    ) -> Result<(), Error> {
+        let mut html = self.render_chapter(ch);
+        let path = self.page_name(ch);
+        log::debug!("Adding path \"{}\"", path);
+
+        let mut children = Vec::new();
+        for sub in merged {
+            log::debug!("Merging \"{}\" into {}", sub, path);
+            let anchor = chapter_anchor(&chapter_page(sub));
+            let body = self.render_chapter(sub);
+            html.push_str(&format!(
+                "<section id=\"{}\">\n{}</section>\n",
+                anchor, body
+            ));
+
+            if self.in_toc(sub) {
+                children.push(TocEntry {
+                    title: toc_title(sub, back_matter),
+                    href: format!("{}#{}", path, anchor),
+                    level: toc_level(sub),
+                });
+            }
+        }
+
+        let html = self.render_page(ch, &path, html, back_matter)?;
+        self.record_file(&path, html.as_bytes());
         let data = Cursor::new(Vec::from(html));
 
-        let path = str::replace(&ch.path.with_extension("html").display().to_string(), "\\", "/");
-        log::debug!("Adding path \"{}\"", path);
-        let mut content = EpubContent::new(path, data).title(format!("{}", ch));
+        self.spine.push(path.clone());
+        let mut content = EpubContent::new(path.clone(), data);
+
+        // pages without a title are left out of the TOC
+        if self.in_toc(ch) {
+            let title = toc_title(ch, back_matter);
+            let level = toc_level(ch);
+            self.toc.push(TocEntry {
+                title: title.clone(),
+                href: path,
+                level,
+            });
+            content = content.title(title).level(level);
+        }
+
+        for child in &children {
+            let element = TocElement::new(child.href.clone(), child.title.clone());
+            content = content.child(element.level(child.level));
+        }
+        self.builder.add_content(content).sync()?;
+        self.toc.extend(children);
+
+        Ok(())
+    }
 
-        let level = ch.number.as_ref().map(|n| n.len() as i32 - 1).unwrap_or(0);
-        content = content.level(level);
+    /// Put a chapter's rendered body into the theme's page template.
+    fn render_page(
+        &self,
+        ch: &Chapter,
+        path: &str,
+        html: String,
+        back_matter: bool,
+    ) -> Result<String, Error> {
+        // stylesheets and lexicons are linked relative to the chapter
+        let to_root = "../".repeat(path.matches('/').count());
+        let stylesheets: Vec<String> = stylesheet_names(&self.config)
+            .into_iter()
+            .map(|name| format!("{}{}", to_root, name))
+            .collect();
 
+        let epub_type = if back_matter {
+            Some("backmatter")
+        } else {
+            None
+        };
+        let html = self.hbs.render(
+            "index",
+            &json!({
+                "content": html,
+                "stylesheets": stylesheets,
+                "lexicons": self.lexicon_links(&to_root),
+                "epub_type": epub_type,
+                "chapter": chapter_context(ch, back_matter),
+                "book": self.book_context(),
+                "config": self.config,
+                "labels": self.labels.template_context(),
+            }),
+        )?;
+
+        Ok(html)
+    }
+
+    /// Render each chapter on its own page, exactly as it would be put into
+    /// the book, without generating anything. Used when checking the book.
+    pub(crate) fn rendered_chapters(
+        &mut self,
+        assets: Vec<Asset>,
+    ) -> Result<Vec<(PathBuf, String)>, Error> {
+        let ctx = self.ctx;
+        self.assets = assets;
+        let (main, back_matter) = included_chapters(&ctx.book.sections, &self.config);
+        let chapters = main
+            .into_iter()
+            .map(|ch| (ch, false))
+            .chain(back_matter.into_iter().map(|ch| (ch, true)));
+
+        let mut pages = Vec::new();
+        for (ch, is_back_matter) in chapters {
+            let html = self.render_chapter(ch);
+            let path = self.page_name(ch);
+            let page = self.render_page(ch, &path, html, is_back_matter)?;
+            pages.push((ch.path.clone(), page));
+        }
+
+        Ok(pages)
+    }
+
+    /// Concatenate every chapter into a single document. Each chapter gets its
+    /// own section and TOC entry, and links between chapters become links
+    /// within the page.
+    fn add_single_page(&mut self) -> Result<(), Error> {
+        log::debug!("Rendering every chapter into {}", SINGLE_PAGE);
+
+        let ctx = self.ctx;
+        let (main, back_matter) = included_chapters(&ctx.book.sections, &self.config);
+
+        let mut body = String::new();
+        let mut toc = Vec::new();
+
+        let chapters = main
+            .iter()
+            .map(|ch| (ch, false))
+            .chain(back_matter.iter().map(|ch| (ch, true)));
+
+        for (ch, is_back_matter) in chapters {
+            log::debug!("Adding chapter \"{}\"", ch);
+            let anchor = chapter_anchor(&chapter_page(ch));
+
+            let html = self.render_chapter(ch);
+            let context = json!({
+                "chapter": chapter_context(ch, is_back_matter),
+                "book": self.book_context(),
+                "config": self.config,
+                "labels": self.labels.template_context(),
+            });
+            // there's only one <body>, so each section says what it is
+            let epub_type = if is_back_matter {
+                " epub:type=\"backmatter\""
+            } else {
+                ""
+            };
+            body.push_str(&format!(
+                "<section id=\"{}\"{}>\n{}{}{}</section>\n",
+                anchor,
+                epub_type,
+                self.hbs.render("header", &context)?,
+                html,
+                self.hbs.render("footer", &context)?
+            ));
+
+            // nested underneath the page's own TOC entry
+            if self.in_toc(ch) {
+                toc.push(TocEntry {
+                    title: format!("{}", ch),
+                    href: format!("{}#{}", SINGLE_PAGE, anchor),
+                    level: toc_level(ch) + 2,
+                });
+            }
+        }
+
+        let html = self.hbs.render(
+            "index",
+            &json!({
+                "content": body,
+                "stylesheets": stylesheet_names(&self.config),
+                "lexicons": self.lexicon_links(""),
+                "labels": self.labels.template_context(),
+            }),
+        )?;
+        self.record_file(SINGLE_PAGE, html.as_bytes());
+
+        let title = match ctx.config.book.title {
+            Some(ref title) => title.clone(),
+            None => self.labels.get(i18n::BOOK).to_string(),
+        };
+        let mut content = EpubContent::new(SINGLE_PAGE, html.as_bytes()).title(title.clone());
+        for entry in &toc {
+            let element = TocElement::new(entry.href.clone(), entry.title.clone());
+            content = content.child(element.level(entry.level));
+        }
         self.builder.add_content(content).sync()?;
 
+        self.spine.push(String::from(SINGLE_PAGE));
+        self.toc.push(TocEntry {
+            title,
+            href: String::from(SINGLE_PAGE),
+            level: 1,
+        });
+        self.toc.extend(toc);
+
         Ok(())
     }
 
-    /// Generate the stylesheet and add it to the document.
+    /// Render a chapter's body to HTML, ready to be put into a page.
+    fn render_chapter(&mut self, ch: &Chapter) -> String {
+        let _scope = logging::scope("chapter", ch.path.display());
+        let html = web_only::strip(&self.render_markdown(ch), &self.config.strip_web_only);
+        let mut html = self.fix_html(html);
+        html = self.rewrite_asset_links(&ch.path, html);
+        if self.relocated {
+            let chapter_dir = ch.path.parent().unwrap_or_else(|| Path::new(""));
+            html = relocate_links(&html, chapter_dir, &self.page_name(ch), &self.locations);
+        }
+        if let Some(ref hyphenator) = self.hyphenator {
+            html = hyphenator.hyphenate_html(&html);
+        }
+        self.used_chars.extend(html.chars());
+        self.used_chars.extend(ch.name.chars());
+
+        html
+    }
+
+    /// The book's metadata, for use in templates.
+    fn book_context(&self) -> serde_json::Value {
+        let book = &self.ctx.config.book;
+        json!({
+            "title": book.title,
+            "authors": book.authors,
+            "description": book.description,
+            "language": book.language,
+        })
+    }
+
+    /// The file a chapter ends up in.
+    fn page_name(&self, ch: &Chapter) -> String {
+        let page = chapter_page(ch);
+        match self.locations.get(&page) {
+            Some(location) => location.file.clone(),
+            None => page,
+        }
+    }
+
+    /// Should the chapter be listed in the table of contents?
+    fn in_toc(&self, ch: &Chapter) -> bool {
+        !self.hidden_from_toc.contains(&ch.path)
+    }
+
+    /// Was the chapter merged into its parent's file?
+    fn is_merged(&self, ch: &Chapter) -> bool {
+        self.locations
+            .get(&chapter_page(ch))
+            .map_or(false, |location| location.anchor.is_some())
+    }
+
+    fn render_markdown(&self, ch: &Chapter) -> String {
+        let options = self.config.markdown;
+
+        self.render_cache
+            .borrow_mut()
+            .entry((ch.path.clone(), options))
+            .or_insert_with(|| markdown::render(&ch.content, &options))
+            .clone()
+    }
+
+    /// Add each stylesheet to the document as a separate file, in the same
+    /// order they're linked from the chapters.
     fn embed_stylesheets(&mut self) -> Result<(), Error> {
         log::debug!("Embedding stylesheets");
 
-        let stylesheet = self
-            .generate_stylesheet()
-            .context("Unable to generate stylesheet")?;
-        self.builder.stylesheet(stylesheet.as_slice()).sync()?;
+        let layers = self
+            .generate_stylesheets()
+            .context("Unable to generate stylesheets")?;
+
+        for (name, stylesheet) in stylesheet_names(&self.config).into_iter().zip(layers) {
+            log::debug!("embed_stylesheet {}", name);
+            self.record_file(&name, &stylesheet);
+
+            if name == DEFAULT_STYLESHEET {
+                // this one is also used by the generated TOC
+                self.builder.stylesheet(stylesheet.as_slice()).sync()?;
+            } else {
+                self.builder
+                    .add_resource(name, Cursor::new(stylesheet), "text/css")
+                    .sync()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn embed_fonts(&mut self) -> Result<(), Error> {
+        log::debug!("Embedding fonts");
+
+        let mut font_files: Vec<PathBuf> = self
+            .config
+            .additional_fonts
+            .iter()
+            .map(|font| self.ctx.root.join(font))
+            .collect();
+
+        // WOFF fonts referenced by the user's stylesheets need converting too
+        for additional_css in &self.config.additional_css {
+            let additional_css = self.ctx.root.join(additional_css);
+            let css = fs::read_to_string(&additional_css)
+                .with_context(|_| format!("Unable to read {}", additional_css.display()))?;
+            let css_dir = additional_css.parent().unwrap_or_else(|| Path::new("."));
+            for font in fonts::woff_fonts_in_css(&css, css_dir) {
+                if !font_files.contains(&font) {
+                    font_files.push(font);
+                }
+            }
+        }
+
+        for path in font_files {
+            let used = if self.config.subset_fonts {
+                Some(&self.used_chars)
+            } else {
+                None
+            };
+            let data = read_font(&path, used)?;
+
+            let (data, extension) = fonts::to_sfnt(data, &path)?;
+            let filename = fonts::embedded_name(&path, extension, &self.embedded)?;
+            let mt = fonts::media_type(extension);
+            log::debug!("embed_font {}", filename);
+            self.record_file(&filename, &data);
+
+            if let Some(original) = path.file_name().and_then(|n| n.to_str()) {
+                self.embedded_fonts
+                    .insert(original.to_string(), filename.clone());
+            }
+
+            self.builder
+                .add_resource(filename, Cursor::new(data), mt)
+                .sync()?;
+        }
 
         Ok(())
     }
@@ -139,65 +1359,775 @@ impl<'a> Generator<'a> {
     fn additional_assets(&mut self) -> Result<(), Error> {
         log::debug!("Embedding additional assets");
 
-        let assets = resources::find(self.ctx)
-            .context("Inspecting the book for additional assets failed")?;
+        let assets = self.assets.clone();
+        let workers = self.config.asset_workers.unwrap_or_else(num_cpus::get);
+        let loaded = load_assets(assets.clone(), &self.config, workers)?;
+
+        for (asset, content) in assets.iter().zip(loaded) {
+            self.embed_asset(asset, content)?;
+        }
+
+        Ok(())
+    }
+
+    /// Point any links to assets which don't match where the asset is
+    /// embedded.
+    fn rewrite_asset_links(&self, chapter: &Path, html: String) -> String {
+        let new_links = asset_links(&self.assets, chapter);
+
+        if new_links.is_empty() {
+            html
+        } else {
+            replace_links(&html, &new_links)
+        }
+    }
+
+    /// Embed the files listed in `additional-resources`.
+    fn additional_resources(&mut self) -> Result<(), Error> {
+        log::debug!("Embedding additional resources");
+
+        for resource in self.config.additional_resources.clone() {
+            let pattern = self.ctx.root.join(&resource.src);
+            let pattern = pattern.to_string_lossy();
+            let mut found = false;
+
+            for path in glob::glob(&pattern)
+                .with_context(|_| format!("Invalid pattern, \"{}\"", resource.src))?
+            {
+                let path = path?;
+                if !path.is_file() {
+                    continue;
+                }
+                found = true;
+
+                let file_name = path.file_name().unwrap().to_string_lossy();
+                let filename = match resource.dest {
+                    Some(ref dest) => format!("{}/{}", dest.trim_end_matches('/'), file_name),
+                    None => file_name.into_owned(),
+                };
+                if self.embedded.contains(&filename) {
+                    log::warn!("{} is already in the book, skipping it", filename);
+                    continue;
+                }
+
+                let mt = match resource.mimetype {
+                    Some(ref mt) => mt.clone(),
+                    None => mime_guess::from_path(&path)
+                        .first_or_octet_stream()
+                        .to_string(),
+                };
+
+                log::debug!("Embedding {} as {}", path.display(), filename);
+                let content = fs::read(&path)
+                    .with_context(|_| format!("Couldn't load {}", path.display()))?;
+                self.record_file(&filename, &content);
+                self.builder
+                    .add_resource(filename, Cursor::new(content), mt)
+                    .sync()?;
+            }
+
+            if !found {
+                log::warn!("\"{}\" didn't match any files", resource.src);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Embed the pronunciation lexicons, in the same order as
+    /// `lexicon::lexicon_names()`.
+    fn embed_lexicons(&mut self) -> Result<(), Error> {
+        let mut lexicons = Vec::new();
 
-        for asset in assets {
-            self.load_asset(&asset)
-                .with_context(|_| format!("Couldn't load {}", asset.filename.display()))?;
+        if let Some(ref lexicon) = self.config.pronunciation_lexicon {
+            let path = self.ctx.root.join(lexicon);
+            let content =
+                fs::read(&path).with_context(|_| format!("Unable to read {}", path.display()))?;
+            lexicons.push(content);
+        }
+        if !self.config.pronunciations.is_empty() {
+            let language = self.ctx.config.book.language.as_ref();
+            let language = language.map(String::as_str).unwrap_or("en");
+            let pls = lexicon::generate_pls(&self.config.pronunciations, language);
+            lexicons.push(pls.into_bytes());
+        }
+
+        for (name, content) in lexicon::lexicon_names(&self.config)
+            .into_iter()
+            .zip(lexicons)
+        {
+            log::debug!("Embedding lexicon {}", name);
+            self.record_file(&name, &content);
+            self.builder
+                .add_resource(name, Cursor::new(content), lexicon::MEDIA_TYPE)
+                .sync()?;
         }
 
         Ok(())
     }
 
-    fn load_asset(&mut self, asset: &Asset) -> Result<(), Error> {
-        let content = File::open(&asset.location_on_disk).context("Unable to open asset")?;
+    /// The pronunciation lexicons to link to from a page, with `to_root`
+    /// being the way back to the `OEBPS/` root.
+    fn lexicon_links(&self, to_root: &str) -> Vec<serde_json::Value> {
+        let language = &self.ctx.config.book.language;
+
+        lexicon::lexicon_names(&self.config)
+            .into_iter()
+            .map(|name| json!({"href": format!("{}{}", to_root, name), "language": language}))
+            .collect()
+    }
 
+    fn embed_asset(&mut self, asset: &Asset, content: Vec<u8>) -> Result<(), Error> {
         let mt = asset.mimetype.to_string();
 
         // Change '\\' to '/'
         let filename = asset.filename.to_str().unwrap();
         let filename = str::replace(&filename, "\\", "/");
         log::debug!("load_asset {}", filename);
+        self.record_file(&filename, &content);
 
         self.builder
-            .add_resource(filename, content, mt)
+            .add_resource(filename, Cursor::new(content), mt)
             .sync()?;
 
         Ok(())
     }
 
-    /// Concatenate all provided stylesheets into one long stylesheet.
-    fn generate_stylesheet(&self) -> Result<Vec<u8>, Error> {
-        let mut stylesheet = Vec::new();
+    /// The spine and table of contents of the book generated so far, along
+    /// with anything which looks wrong with them.
+    pub fn spine_report(&self) -> SpineReport {
+        SpineReport::new(self.spine.clone(), self.toc.clone())
+    }
+
+    /// How long each phase of generating the book took.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// The checksums of every file embedded so far (only populated when
+    /// `checksums` is enabled).
+    pub fn checksums(&self) -> &BTreeMap<String, String> {
+        &self.checksums
+    }
+
+    /// Keep track of a file added to the book (and its checksum, if they're
+    /// wanted).
+    fn record_file(&mut self, path: &str, data: &[u8]) {
+        self.embedded.insert(path.to_string());
+
+        if self.config.checksums {
+            let digest = format!("{:x}", Sha256::digest(data));
+            self.checksums.insert(format!("OEBPS/{}", path), digest);
+        }
+    }
+
+    /// Generate the contents of each stylesheet, in cascade order: the
+    /// default stylesheet, then the typography settings, then the user's own
+    /// stylesheets.
+    fn generate_stylesheets(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut layers = Vec::new();
 
         if self.config.use_default_css {
-            stylesheet.extend(DEFAULT_CSS.as_bytes());
+            layers.push(self.default_css.clone().into_bytes());
         }
 
-        for additional_css in &self.config.additional_css {
+        let typography = self.config.typography.stylesheet();
+        if !typography.is_empty() {
+            layers.push(typography.into_bytes());
+        }
+
+        let names = stylesheet_names(&self.config);
+        let additional_names = &names[names.len() - self.config.additional_css.len()..];
+
+        for (additional_css, name) in self.config.additional_css.iter().zip(additional_names) {
+            // relative to the book's root, the same as `check` looks for it
+            let additional_css = self.ctx.root.join(additional_css);
             let mut f = File::open(&additional_css)
                 .with_context(|_| format!("Unable to open {}", additional_css.display()))?;
-            f.read_to_end(&mut stylesheet)
+            let mut css = String::new();
+            f.read_to_string(&mut css)
                 .context("Error reading stylesheet")?;
+
+            // urls were written relative to the root, but the stylesheet
+            // lives in its own directory
+            let to_root = "../".repeat(name.matches('/').count());
+            let css = fonts::rewrite_css_urls(&css, &self.embedded_fonts, &to_root);
+            layers.push(css.into_bytes());
         }
 
-        Ok(stylesheet)
+        Ok(layers)
     }
 
     fn fix_html(&self, html: String) -> String {
         let html = self.fix_img(html);
+        let html = self.fix_task_list(html);
         return html;
     }
 
+    fn fix_task_list(&self, html: String) -> String {
+        let completed = utils::escape_html(self.labels.get(i18n::COMPLETED));
+        let not_completed = utils::escape_html(self.labels.get(i18n::NOT_COMPLETED));
+        fix_task_list(&html, &completed, &not_completed)
+    }
+
     fn fix_img(&self, html: String) -> String {
-        lazy_static! {
-            static ref IMG: Regex =
-                    Regex::new(r"(?P<img><img\s+[^>]*/>)").unwrap();
+        wrap_images(&html, self.config.image_links, self.config.alt_captions)
+    }
+}
+
+/// The links in a chapter which need to change to reach the embedded assets,
+/// keyed by the original link. An asset may have been renamed (e.g. to avoid a
+/// collision or fix the case), or this chapter may have reached it through a
+/// different path (e.g. a symlink) than the one it was embedded under.
+fn asset_links<'b>(assets: &'b [Asset], chapter: &Path) -> HashMap<&'b str, String> {
+    let chapter_dir = chapter.parent().unwrap_or_else(|| Path::new(""));
+    let mut new_links = HashMap::new();
+
+    for asset in assets {
+        let new_link = links::relative_path(chapter_dir, &asset.filename);
+
+        for (_, link) in asset.references.iter().filter(|(ch, _)| ch == chapter) {
+            if *link != new_link {
+                new_links.insert(link.as_str(), new_link.clone());
+            }
+        }
+    }
+
+    new_links
+}
+
+/// Replace the `src`, `href` and `data` attributes of any tag which link to
+/// one of the `new_links`, leaving the rest of the document alone.
+fn replace_links(html: &str, new_links: &HashMap<&str, String>) -> String {
+    lazy_static! {
+        static ref TAG: Regex = Regex::new(r"<[a-zA-Z][^>]*>").unwrap();
+        static ref LINK: Regex = Regex::new(r#"(\s(?:src|href|data)=")([^"]*)""#).unwrap();
+    }
+
+    TAG.replace_all(html, |tag: &Captures<'_>| {
+        LINK.replace_all(&tag[0], |attr: &Captures<'_>| {
+            match new_links.get(&attr[2]) {
+                Some(new_link) => format!("{}{}\"", &attr[1], new_link),
+                None => attr[0].to_string(),
+            }
+        })
+        .into_owned()
+    })
+    .into_owned()
+}
+
+/// Form controls don't belong in an ebook, so replace task list checkboxes
+/// with a symbol (and a label for screen readers). The checkbox comes straight
+/// after the `<li>` in tight lists, and inside the item's `<p>` in loose ones.
+fn fix_task_list(html: &str, completed: &str, not_completed: &str) -> String {
+    lazy_static! {
+        static ref TASK: Regex = Regex::new(concat!(
+            r#"<li>(?P<before>(?:\s*<[a-z]+>)*)\s*"#,
+            r#"<input\s+disabled=""\s+type="checkbox"(?P<checked>\s+checked="")?\s*/>\s*"#,
+        ))
+        .unwrap();
+    }
+
+    TASK.replace_all(html, |caps: &Captures<'_>| {
+        let (symbol, label) = if caps.name("checked").is_some() {
+            ("\u{2611}", completed)
+        } else {
+            ("\u{2610}", not_completed)
+        };
+
+        format!(
+            "<li class=\"task-list-item\">{}<span class=\"task-list-item-checkbox\" \
+             role=\"img\" aria-label=\"{}\">{}</span> ",
+            &caps["before"], label, symbol
+        )
+    })
+    .into_owned()
+}
+
+/// As per the EPUB standard, images should be inside a block element, so put
+/// each `<img ... />` into a `<p>` (or a `<figure>` when its alt text is used
+/// as a caption). Images wrapped in a link are kept whole, so the `<p>`
+/// doesn't end up in the middle of the `<a>`.
+fn wrap_images(html: &str, links: ImageLinks, alt_captions: bool) -> String {
+    lazy_static! {
+        static ref IMAGE_PARAGRAPH: Regex =
+            Regex::new(r#"<p>\s*(?P<img>(?:<a\s[^>]*>\s*)?<img\s+[^>]*/>(?:\s*</a>)?)\s*</p>"#)
+                .unwrap();
+        static ref IMG: Regex =
+            Regex::new(r#"<a\s[^>]*>\s*(?P<linked><img\s+[^>]*/>)\s*</a>|(?P<img><img\s+[^>]*/>)"#)
+                .unwrap();
+        static ref SRC: Regex = Regex::new(r#"\ssrc="([^"]*)""#).unwrap();
+        static ref ALT: Regex = Regex::new(r#"\salt="([^"]+)""#).unwrap();
+    }
+
+    // paragraphs which only contain an image are replaced below
+    let html = IMAGE_PARAGRAPH.replace_all(html, "$img");
+
+    let wrap = |block: &str, img: &str| {
+        let caption = if alt_captions && !img.contains(" title=\"") {
+            ALT.captures(img)
+        } else {
+            None
+        };
+
+        match caption {
+            Some(alt) => format!(
+                "<figure>{}<figcaption>{}</figcaption></figure>",
+                block, &alt[1]
+            ),
+            None => format!("<p>{}</p>", block),
+        }
+    };
+
+    IMG.replace_all(&html, |caps: &Captures<'_>| {
+        let img = match caps.name("linked") {
+            Some(img) => img.as_str(),
+            None => return wrap(&caps["img"], &caps["img"]),
+        };
+
+        match links {
+            ImageLinks::Keep => wrap(&caps[0], img),
+            ImageLinks::Strip => wrap(img, img),
+            ImageLinks::FullSize => match SRC.captures(img) {
+                Some(src) => wrap(&format!("<a href=\"{}\">{}</a>", &src[1], img), img),
+                None => wrap(img, img),
+            },
+        }
+    })
+    .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdbook::book::{Book, SectionNumber};
+    use mdbook::config::Config as MdConfig;
+    use tempdir::TempDir;
+
+    #[test]
+    fn suffix_chapters_are_back_matter() {
+        let chapter = |name: &str, number: Option<u32>| {
+            let mut ch = Chapter::new(name, String::new(), format!("{}.md", name), Vec::new());
+            ch.number = number.map(|n| SectionNumber(vec![n]));
+            BookItem::Chapter(ch)
+        };
+        let sections = vec![
+            chapter("foreword", None),
+            chapter("intro", Some(1)),
+            BookItem::Separator,
+            chapter("usage", Some(2)),
+            chapter("glossary", None),
+            chapter("index", None),
+        ];
+
+        let (main, back_matter) = split_back_matter(&sections);
+
+        let names = |chapters: Vec<&Chapter>| -> Vec<String> {
+            chapters.into_iter().map(|ch| ch.name.clone()).collect()
+        };
+        assert_eq!(names(main), vec!["foreword", "intro", "usage"]);
+        assert_eq!(names(back_matter), vec!["glossary", "index"]);
+    }
+
+    #[test]
+    fn nested_chapters_are_merged_below_the_page_break_level() {
+        let mut usage = Chapter::new("usage", String::new(), "usage.md", Vec::new());
+        usage.number = Some(SectionNumber(vec![1]));
+        let mut setup = Chapter::new("setup", String::new(), "setup.md", vec![usage.name.clone()]);
+        setup.number = Some(SectionNumber(vec![1, 1]));
+        usage.sub_items.push(BookItem::Chapter(setup));
+        let sections = vec![BookItem::Chapter(usage)];
+        let config = Config {
+            page_break_level: Some(1),
+            ..Config::default()
+        };
+
+        let got = chapter_locations(&sections, &config);
+
+        assert_eq!(got["usage.html"].file, "usage.html");
+        assert_eq!(got["usage.html"].anchor, None);
+        assert_eq!(got["setup.html"].file, "usage.html");
+        assert_eq!(
+            got["setup.html"].anchor,
+            Some(String::from("chapter-setup"))
+        );
+    }
+
+    #[test]
+    fn linked_images_are_wrapped_whole() {
+        let src = r#"<a href="a.png"><img src="b.png" /></a> <img src="c.png" />"#;
+        let inputs = vec![
+            (
+                ImageLinks::Keep,
+                r#"<p><a href="a.png"><img src="b.png" /></a></p> <p><img src="c.png" /></p>"#,
+            ),
+            (
+                ImageLinks::Strip,
+                r#"<p><img src="b.png" /></p> <p><img src="c.png" /></p>"#,
+            ),
+            (
+                ImageLinks::FullSize,
+                r#"<p><a href="b.png"><img src="b.png" /></a></p> <p><img src="c.png" /></p>"#,
+            ),
+        ];
+
+        for (links, should_be) in inputs {
+            assert_eq!(wrap_images(src, links, false), should_be);
+        }
+    }
+
+    #[test]
+    fn alt_text_becomes_a_caption() {
+        let src = "<p><img src=\"a.png\" alt=\"A map\" /></p>\n\
+                   <p><img src=\"b.png\" alt=\"A chart\" title=\"Sales\" /></p>";
+        let should_be = "<figure><img src=\"a.png\" alt=\"A map\" /><figcaption>A map</figcaption>\
+                         </figure>\n<p><img src=\"b.png\" alt=\"A chart\" title=\"Sales\" /></p>";
+
+        assert_eq!(wrap_images(src, ImageLinks::Keep, true), should_be);
+    }
+
+    #[test]
+    fn collapsed_chapters_hide_their_children() {
+        let chapter = |path: &str| Chapter::new(path, String::new(), path, Vec::new());
+        let mut reference = chapter("reference.md");
+        let mut functions = chapter("reference/functions.md");
+        functions
+            .sub_items
+            .push(BookItem::Chapter(chapter("reference/abs.md")));
+        reference.sub_items.push(BookItem::Chapter(functions));
+        let sections = vec![BookItem::Chapter(reference)];
+
+        let got = hidden_from_toc(&sections, &[PathBuf::from("reference.md")]);
+
+        assert_eq!(got.len(), 2);
+        assert!(!got.contains(Path::new("reference.md")));
+        assert!(got.contains(Path::new("reference/abs.md")));
+    }
+
+    #[test]
+    fn links_to_merged_chapters() {
+        let mut locations = HashMap::new();
+        for page in &["intro.html", "guide/usage.html"] {
+            let location = Location {
+                file: String::from(SINGLE_PAGE),
+                anchor: Some(chapter_anchor(page)),
+            };
+            locations.insert(page.to_string(), location);
+        }
+        let src = concat!(
+            r#"<a href="../intro.html">a</a> <a href="usage.html#setup">b</a> "#,
+            r#"<img src="logo.png" /> <a href="https://rust-lang.org/">c</a>"#,
+        );
+        let should_be = concat!(
+            r#"<a href="#chapter-intro">a</a> <a href="#setup">b</a> "#,
+            r#"<img src="guide/logo.png" /> <a href="https://rust-lang.org/">c</a>"#,
+        );
+
+        let got = relocate_links(src, Path::new("guide"), SINGLE_PAGE, &locations);
+
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn pages_with_the_same_slug_get_different_files() {
+        let chapter = |path: &str| {
+            let name = path.trim_end_matches(".md");
+            BookItem::Chapter(Chapter::new(name, String::new(), path, Vec::new()))
+        };
+        let sections = vec![
+            chapter("Über.md"),
+            chapter("uber.md"),
+            chapter("a b.md"),
+            chapter("a-b.md"),
+        ];
+        let config = Config {
+            slugify_filenames: true,
+            ..Config::default()
+        };
+
+        let got = chapter_locations(&sections, &config);
+
+        let file = |page: &str| got[page].file.as_str();
+        assert_eq!(file("Über.html"), "uber.html");
+        assert_eq!(file("uber.html"), "uber-2.html");
+        assert_eq!(file("a b.html"), "a-b.html");
+        assert_eq!(file("a-b.html"), "a-b-2.html");
+    }
+
+    #[test]
+    fn derived_descriptions_are_escaped() {
+        let mut book = Book::new();
+        let content = "# Intro\n\nA `Vec<T>` & friends.\n";
+        book.push_item(Chapter::new(
+            "Intro",
+            content.to_string(),
+            "intro.md",
+            Vec::new(),
+        ));
+        let ctx = RenderContext::new("", book, MdConfig::default(), "");
+
+        let got = book_description(&ctx, &Config::default());
+
+        assert_eq!(got, Some(String::from("A Vec&lt;T&gt; &amp; friends.")));
+    }
+
+    #[test]
+    fn tight_task_lists_become_symbols() {
+        let html = concat!(
+            "<ul>\n<li><input disabled=\"\" type=\"checkbox\" checked=\"\"/>\ndone</li>\n",
+            "<li><input disabled=\"\" type=\"checkbox\"/>\ntodo</li>\n</ul>\n",
+        );
+        let should_be = concat!(
+            "<ul>\n<li class=\"task-list-item\"><span class=\"task-list-item-checkbox\" ",
+            "role=\"img\" aria-label=\"Completed\">\u{2611}</span> done</li>\n",
+            "<li class=\"task-list-item\"><span class=\"task-list-item-checkbox\" ",
+            "role=\"img\" aria-label=\"Not completed\">\u{2610}</span> todo</li>\n</ul>\n",
+        );
+
+        let got = fix_task_list(html, "Completed", "Not completed");
+
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn loose_task_lists_become_symbols() {
+        let html = concat!(
+            "<ul>\n<li>\n<p><input disabled=\"\" type=\"checkbox\" checked=\"\"/>\n",
+            "done</p>\n</li>\n</ul>\n",
+        );
+        let should_be = concat!(
+            "<ul>\n<li class=\"task-list-item\">\n<p><span class=\"task-list-item-checkbox\" ",
+            "role=\"img\" aria-label=\"Erledigt\">\u{2611}</span> done</p>\n</li>\n</ul>\n",
+        );
+
+        let got = fix_task_list(html, "Erledigt", "Nicht erledigt");
+
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn fonts_which_cant_be_subset_are_embedded_whole() {
+        let temp = TempDir::new("mdbook-epub").unwrap();
+        let font = temp.path().join("broken.ttf");
+        fs::write(&font, b"not really a font").unwrap();
+        let used: BTreeSet<char> = "abc".chars().collect();
+
+        let got = read_font(&font, Some(&used)).unwrap();
+
+        assert_eq!(got, b"not really a font");
+    }
+
+    #[test]
+    fn spine_pages_follow_the_config() {
+        let config = Config {
+            spine: vec![
+                SpinePage::Toc,
+                SpinePage::BackMatter,
+                SpinePage::TitlePage,
+                SpinePage::Chapters,
+            ],
+            ..Config::default()
+        };
+
+        let got = spine_pages(&config);
+
+        assert_eq!(got, config.spine);
+    }
+
+    #[test]
+    fn missing_spine_pages_are_filled_in() {
+        let config = Config {
+            spine: vec![SpinePage::Cover, SpinePage::Toc],
+            dedication: Some(String::from("For Ferris")),
+            acknowledgments: Some(String::from("Thanks")),
+            ..Config::default()
+        };
+        let should_be = vec![
+            SpinePage::Cover,
+            SpinePage::Toc,
+            SpinePage::Dedication,
+            SpinePage::Acknowledgments,
+            SpinePage::Chapters,
+            SpinePage::BackMatter,
+        ];
+
+        let got = spine_pages(&config);
+
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn a_dedication_already_in_the_spine_stays_put() {
+        let config = Config {
+            spine: vec![SpinePage::Chapters, SpinePage::Dedication],
+            dedication: Some(String::from("For Ferris")),
+            ..Config::default()
+        };
+        let should_be = vec![
+            SpinePage::Chapters,
+            SpinePage::BackMatter,
+            SpinePage::Dedication,
+        ];
+
+        let got = spine_pages(&config);
+
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn chapters_get_the_themes_header_and_footer() {
+        let temp = TempDir::new("mdbook-epub").unwrap();
+        let theme_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("theme");
+        fs::copy(theme_dir.join("index.hbs"), temp.path().join("index.hbs")).unwrap();
+        fs::write(
+            temp.path().join("header.hbs"),
+            "<header>{{chapter.name}}</header>",
+        )
+        .unwrap();
+        fs::write(
+            temp.path().join("footer.hbs"),
+            "<footer>{{book.title}}</footer>",
+        )
+        .unwrap();
+        let hbs = load_templates(temp.path()).unwrap();
+        let ch = Chapter::new("Intro", String::new(), "intro.md", Vec::new());
+
+        let chapter = hbs
+            .render(
+                "index",
+                &json!({
+                    "content": "<p>Hello</p>",
+                    "chapter": chapter_context(&ch, false),
+                    "book": {"title": "My Book"},
+                }),
+            )
+            .unwrap();
+        let generated_page = hbs
+            .render("index", &json!({"content": "<p>Hello</p>"}))
+            .unwrap();
+
+        assert!(chapter.contains("<header>Intro</header>"), "{}", chapter);
+        assert!(chapter.contains("<footer>My Book</footer>"), "{}", chapter);
+        assert!(!generated_page.contains("<header>"), "{}", generated_page);
+        assert!(!generated_page.contains("<footer>"), "{}", generated_page);
+    }
+
+    #[test]
+    fn links_which_reach_an_asset_another_way_are_rewritten() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dummy");
+        let mut book = Book::new();
+        for (path, link) in &[
+            ("chapter_1.md", "rust-logo.png"),
+            ("other.md", "./rust-logo.png"),
+        ] {
+            let content = format!("![logo]({})", link);
+            book.push_item(Chapter::new(path, content, *path, Vec::new()));
         }
+        let ctx = RenderContext::new(root, book, MdConfig::default(), "");
+        let assets = resources::find(&ctx).unwrap();
+
+        assert_eq!(assets.len(), 1);
+        assert!(asset_links(&assets, Path::new("chapter_1.md")).is_empty());
+        let got = asset_links(&assets, Path::new("other.md"));
+        assert_eq!(
+            got.get("./rust-logo.png"),
+            Some(&String::from("rust-logo.png"))
+        );
+    }
+
+    #[test]
+    fn assets_which_only_differ_by_case_are_renamed() {
+        let temp = TempDir::new("mdbook-epub").unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir(&src).unwrap();
+        for name in &["img.png", "IMG.png", "logo.png"] {
+            fs::write(src.join(name), "the same picture").unwrap();
+        }
+        let content = "![a](img.png) ![b](IMG.png) ![c](logo.png)";
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Chapter",
+            content.to_string(),
+            "chapter.md",
+            Vec::new(),
+        ));
+        let ctx = RenderContext::new(temp.path(), book, MdConfig::default(), "");
+
+        let assets = resources::find(&ctx).unwrap();
+
+        let names: Vec<String> = assets
+            .iter()
+            .map(|asset| asset.filename.display().to_string())
+            .collect();
+        assert_eq!(names.len(), 3);
+        assert!(names[0].starts_with("img-") && names[0].ends_with(".png"));
+        assert!(names[1].starts_with("IMG-") && names[1].ends_with(".png"));
+        assert_eq!(names[0].len(), "img-12345678.png".len());
+        assert_ne!(names[0].to_lowercase(), names[1].to_lowercase());
+        assert_eq!(names[2], "logo.png");
+
+        let got = asset_links(&assets, Path::new("chapter.md"));
+        assert_eq!(got.len(), 2);
+        assert_eq!(got["img.png"], names[0]);
+        assert_eq!(got["IMG.png"], names[1]);
+    }
+
+    #[test]
+    fn only_rewrite_links_inside_tags() {
+        let mut new_links = HashMap::new();
+        new_links.insert("../images/logo.png", String::from("../assets/logo.png"));
+        let src = concat!(
+            r#"<p><img src="../images/logo.png" alt="logo" /> "#,
+            r#"<a href="../images/logo.png">src="../images/logo.png"</a></p>"#,
+        );
+        let should_be = concat!(
+            r#"<p><img src="../assets/logo.png" alt="logo" /> "#,
+            r#"<a href="../assets/logo.png">src="../images/logo.png"</a></p>"#,
+        );
+
+        let got = replace_links(src, &new_links);
+
+        assert_eq!(got, should_be);
+    }
+
+    fn text_assets(dir: &Path, count: usize) -> Vec<Asset> {
+        (0..count)
+            .map(|i| {
+                let filename = PathBuf::from(format!("notes-{}.txt", i));
+                let location_on_disk = dir.join(&filename);
+                fs::write(&location_on_disk, format!("note {}", i)).unwrap();
+
+                Asset {
+                    mimetype: mime_guess::from_path(&filename).first_or_octet_stream(),
+                    location_on_disk,
+                    filename,
+                    references: Vec::new(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loaded_assets_come_back_in_order() {
+        let temp = TempDir::new("mdbook-epub").unwrap();
+        let assets = text_assets(temp.path(), 50);
+        let should_be: Vec<Vec<u8>> = (0..50)
+            .map(|i| format!("note {}", i).into_bytes())
+            .collect();
+
+        let got = load_assets(assets, &Config::default(), 4).unwrap();
+
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn asset_errors_stop_the_pool() {
+        let temp = TempDir::new("mdbook-epub").unwrap();
+        let assets = text_assets(temp.path(), 50);
+        fs::remove_file(&assets[17].location_on_disk).unwrap();
+
+        let got = load_assets(assets, &Config::default(), 4).unwrap_err();
 
-        // As epub standard, img should be inside a block element.
-        // So here, always put <img ... /> into a <p>.
-        return IMG.replace_all(&html, "<p>$img</p>").to_string();
+        assert_eq!(got.to_string(), "Couldn't load notes-17.txt");
     }
 }
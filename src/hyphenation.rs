@@ -0,0 +1,287 @@
+//! A small implementation of Liang's hyphenation algorithm, used to insert
+//! soft hyphens into the rendered chapters.
+//!
+//! Patterns are read from a plain text file in the format distributed by the
+//! [hyph-utf8] project (e.g. `hyph-en-us.pat.txt`). Tokens containing a `-`
+//! are treated as explicit exceptions.
+//!
+//! [hyph-utf8]: http://www.hyphenation.org/
+
+use failure::{Error, ResultExt};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// The soft hyphen character. We can't use `&shy;` because XHTML only knows
+/// about the predefined XML entities.
+const SOFT_HYPHEN: char = '\u{ad}';
+
+/// The minimum number of characters to leave before the first hyphen.
+const LEFT_MIN: usize = 2;
+/// The minimum number of characters to leave after the last hyphen.
+const RIGHT_MIN: usize = 3;
+
+/// Elements whose text should never be hyphenated.
+const SKIPPED_ELEMENTS: &[&str] = &[
+    "code", "pre", "kbd", "samp", "script", "style", "tt", "rt", "rp",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Hyphenator {
+    patterns: HashMap<String, Vec<u8>>,
+    exceptions: HashMap<String, Vec<usize>>,
+    max_pattern_len: usize,
+}
+
+impl Hyphenator {
+    /// Load a set of hyphenation patterns from disk.
+    pub(crate) fn load(path: &Path) -> Result<Hyphenator, Error> {
+        let text = fs::read_to_string(path).with_context(|_| {
+            format!(
+                "Unable to read hyphenation patterns from {}",
+                path.display()
+            )
+        })?;
+
+        Ok(Hyphenator::from_patterns(&text))
+    }
+
+    pub(crate) fn from_patterns(text: &str) -> Hyphenator {
+        let mut patterns = HashMap::new();
+        let mut exceptions = HashMap::new();
+        let mut max_pattern_len = 0;
+
+        for line in text.lines() {
+            // TeX style comments
+            let line = line.split('%').next().unwrap_or_default();
+
+            for token in line.split_whitespace() {
+                if token.contains('{') || token.contains('}') {
+                    continue;
+                }
+
+                if token.contains('-') {
+                    let mut word = String::new();
+                    let mut breaks = Vec::new();
+                    for c in token.chars() {
+                        if c == '-' {
+                            breaks.push(word.chars().count());
+                        } else {
+                            word.extend(c.to_lowercase());
+                        }
+                    }
+                    exceptions.insert(word, breaks);
+                    continue;
+                }
+
+                let mut letters = String::new();
+                let mut values = vec![0];
+                for c in token.chars() {
+                    match c.to_digit(10) {
+                        Some(d) => *values.last_mut().unwrap() = d as u8,
+                        None => {
+                            letters.push(c);
+                            values.push(0);
+                        }
+                    }
+                }
+
+                max_pattern_len = max_pattern_len.max(letters.chars().count());
+                patterns.insert(letters, values);
+            }
+        }
+
+        Hyphenator {
+            patterns,
+            exceptions,
+            max_pattern_len,
+        }
+    }
+
+    /// Find the character offsets within `word` where a hyphen may be
+    /// inserted.
+    fn breaks(&self, word: &str) -> Vec<usize> {
+        let lower: String = word.chars().flat_map(char::to_lowercase).collect();
+        let len = lower.chars().count();
+
+        if len != word.chars().count() || len < LEFT_MIN + RIGHT_MIN {
+            return Vec::new();
+        }
+
+        if let Some(breaks) = self.exceptions.get(&lower) {
+            return breaks.clone();
+        }
+
+        let chars: Vec<char> = format!(".{}.", lower).chars().collect();
+        let mut points = vec![0_u8; chars.len() + 1];
+
+        for start in 0..chars.len() {
+            let end = chars.len().min(start + self.max_pattern_len);
+            for stop in start + 1..=end {
+                let candidate: String = chars[start..stop].iter().collect();
+                if let Some(values) = self.patterns.get(&candidate) {
+                    for (i, &value) in values.iter().enumerate() {
+                        let p = &mut points[start + i];
+                        *p = (*p).max(value);
+                    }
+                }
+            }
+        }
+
+        // points[i + 1] is the value between the (i - 1)'th and i'th
+        // character of the word because of the leading "."
+        (LEFT_MIN..=len - RIGHT_MIN)
+            .filter(|&i| points[i + 1] % 2 == 1)
+            .collect()
+    }
+
+    /// Insert soft hyphens into a single word.
+    pub(crate) fn hyphenate_word(&self, word: &str) -> String {
+        let breaks = self.breaks(word);
+        let mut hyphenated = String::with_capacity(word.len() + breaks.len() * 2);
+
+        for (i, c) in word.chars().enumerate() {
+            if breaks.contains(&i) {
+                hyphenated.push(SOFT_HYPHEN);
+            }
+            hyphenated.push(c);
+        }
+
+        hyphenated
+    }
+
+    /// Hyphenate the body text of a rendered chapter, leaving markup, code
+    /// and URLs alone.
+    pub(crate) fn hyphenate_html(&self, html: &str) -> String {
+        let mut out = String::with_capacity(html.len());
+        let mut skip_depth = 0_usize;
+        let mut rest = html;
+
+        while !rest.is_empty() {
+            if rest.starts_with('<') {
+                let end = rest.find('>').map(|i| i + 1).unwrap_or_else(|| rest.len());
+                let tag = &rest[..end];
+
+                if let Some(name) = tag_name(tag) {
+                    if SKIPPED_ELEMENTS.contains(&name.as_str()) && !tag.ends_with("/>") {
+                        if tag.starts_with("</") {
+                            skip_depth = skip_depth.saturating_sub(1);
+                        } else {
+                            skip_depth += 1;
+                        }
+                    }
+                }
+
+                out.push_str(tag);
+                rest = &rest[end..];
+            } else {
+                let end = rest.find('<').unwrap_or_else(|| rest.len());
+                let text = &rest[..end];
+
+                if skip_depth > 0 {
+                    out.push_str(text);
+                } else {
+                    self.hyphenate_text(text, &mut out);
+                }
+                rest = &rest[end..];
+            }
+        }
+
+        out
+    }
+
+    fn hyphenate_text(&self, text: &str, out: &mut String) {
+        let mut token_start = None;
+
+        for (i, c) in text.char_indices() {
+            if c.is_whitespace() {
+                if let Some(start) = token_start.take() {
+                    self.hyphenate_token(&text[start..i], out);
+                }
+                out.push(c);
+            } else if token_start.is_none() {
+                token_start = Some(i);
+            }
+        }
+
+        if let Some(start) = token_start {
+            self.hyphenate_token(&text[start..], out);
+        }
+    }
+
+    fn hyphenate_token(&self, token: &str, out: &mut String) {
+        if token.contains("://") || token.starts_with("www.") || token.contains('@') {
+            out.push_str(token);
+            return;
+        }
+
+        let mut word = String::new();
+        let mut chars = token.chars();
+
+        while let Some(c) = chars.next() {
+            if c.is_alphabetic() {
+                word.push(c);
+                continue;
+            }
+
+            out.push_str(&self.hyphenate_word(&word));
+            word.clear();
+            out.push(c);
+
+            // copy entities through verbatim
+            if c == '&' {
+                for c in &mut chars {
+                    out.push(c);
+                    if c == ';' {
+                        break;
+                    }
+                }
+            }
+        }
+
+        out.push_str(&self.hyphenate_word(&word));
+    }
+}
+
+/// Get the lower-cased element name from an opening or closing tag.
+fn tag_name(tag: &str) -> Option<String> {
+    let name: String = tag
+        .trim_start_matches('<')
+        .trim_start_matches('/')
+        .chars()
+        .take_while(|c| c.is_alphanumeric())
+        .collect();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERNS: &str = "% a couple of patterns from hyph-en-us\n\
+                            1ta 1tio n1a 2io tion1 hy3ph he2n\n\
+                            hy-phen-ation\n";
+
+    #[test]
+    fn hyphenate_a_single_word() {
+        let h = Hyphenator::from_patterns(PATTERNS);
+
+        assert_eq!(h.hyphenate_word("nation"), "na\u{ad}tion");
+        assert_eq!(h.hyphenate_word("Hyphenation"), "Hy\u{ad}phen\u{ad}ation");
+        assert_eq!(h.hyphenate_word("ta"), "ta");
+    }
+
+    #[test]
+    fn code_and_urls_are_left_alone() {
+        let h = Hyphenator::from_patterns(PATTERNS);
+        let src = "<p>nation <code>nation</code> https://nation.com/nation</p>";
+        let should_be = "<p>na\u{ad}tion <code>nation</code> https://nation.com/nation</p>";
+
+        assert_eq!(h.hyphenate_html(src), should_be);
+    }
+}
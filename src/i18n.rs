@@ -0,0 +1,210 @@
+//! Translations of the little bits of text we generate ourselves, so they
+//! match the book's language.
+
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+
+/// The heading of the table of contents.
+pub(crate) const TABLE_OF_CONTENTS: &str = "table-of-contents";
+/// The title of the cover page.
+pub(crate) const COVER: &str = "cover";
+/// The title of the copyright page.
+pub(crate) const COPYRIGHT: &str = "copyright";
+/// The title of the dedication page.
+pub(crate) const DEDICATION: &str = "dedication";
+/// The heading of the acknowledgments page.
+pub(crate) const ACKNOWLEDGMENTS: &str = "acknowledgments";
+/// The title of the single page when the book doesn't have one.
+pub(crate) const BOOK: &str = "book";
+/// What screen readers say for a ticked task list item.
+pub(crate) const COMPLETED: &str = "completed";
+/// What screen readers say for an unticked task list item.
+pub(crate) const NOT_COMPLETED: &str = "not-completed";
+/// The heading of a list of figures (available to themes).
+pub(crate) const LIST_OF_FIGURES: &str = "list-of-figures";
+/// The heading of a chapter's notes (available to themes).
+pub(crate) const NOTES: &str = "notes";
+/// The title of the title page.
+pub(crate) const TITLE_PAGE: &str = "title-page";
+
+const KEYS: &[&str] = &[
+    TABLE_OF_CONTENTS,
+    COVER,
+    COPYRIGHT,
+    DEDICATION,
+    ACKNOWLEDGMENTS,
+    BOOK,
+    COMPLETED,
+    NOT_COMPLETED,
+    LIST_OF_FIGURES,
+    NOTES,
+    TITLE_PAGE,
+];
+
+/// The built-in translations for each language, in the same order as `KEYS`.
+/// The first one is used when we don't know the book's language.
+#[rustfmt::skip]
+const TRANSLATIONS: &[(&str, &[&str])] = &[
+    ("en", &[
+        "Table of Contents", "Cover", "Copyright", "Dedication", "Acknowledgments", "Book",
+        "Completed", "Not completed", "List of Figures", "Notes", "Title Page",
+    ]),
+    ("de", &[
+        "Inhaltsverzeichnis", "Umschlag", "Impressum", "Widmung", "Danksagung", "Buch",
+        "Erledigt", "Nicht erledigt", "Abbildungsverzeichnis", "Anmerkungen", "Titelseite",
+    ]),
+    ("es", &[
+        "Índice", "Portada", "Derechos de autor", "Dedicatoria", "Agradecimientos", "Libro",
+        "Completado", "No completado", "Índice de figuras", "Notas", "Portadilla",
+    ]),
+    ("fr", &[
+        "Table des matières", "Couverture", "Droits d'auteur", "Dédicace", "Remerciements",
+        "Livre", "Terminé", "Non terminé", "Table des figures", "Notes", "Page de titre",
+    ]),
+    ("it", &[
+        "Indice", "Copertina", "Copyright", "Dedica", "Ringraziamenti", "Libro",
+        "Completato", "Non completato", "Indice delle figure", "Note", "Frontespizio",
+    ]),
+    ("ja", &[
+        "目次", "表紙", "著作権", "献辞", "謝辞", "本",
+        "完了", "未完了", "図目次", "注", "扉",
+    ]),
+    ("ko", &[
+        "목차", "표지", "저작권", "헌사", "감사의 글", "책",
+        "완료", "미완료", "그림 목차", "주석", "속표지",
+    ]),
+    ("nl", &[
+        "Inhoudsopgave", "Omslag", "Colofon", "Opdracht", "Dankwoord", "Boek",
+        "Voltooid", "Niet voltooid", "Lijst van figuren", "Noten", "Titelpagina",
+    ]),
+    ("pl", &[
+        "Spis treści", "Okładka", "Prawa autorskie", "Dedykacja", "Podziękowania", "Książka",
+        "Ukończone", "Nieukończone", "Spis ilustracji", "Przypisy", "Strona tytułowa",
+    ]),
+    ("pt", &[
+        "Sumário", "Capa", "Direitos autorais", "Dedicatória", "Agradecimentos", "Livro",
+        "Concluído", "Não concluído", "Lista de figuras", "Notas", "Folha de rosto",
+    ]),
+    ("ru", &[
+        "Содержание", "Обложка", "Авторские права", "Посвящение", "Благодарности", "Книга",
+        "Выполнено", "Не выполнено", "Список иллюстраций", "Примечания", "Титульный лист",
+    ]),
+    ("sv", &[
+        "Innehåll", "Omslag", "Upphovsrätt", "Dedikation", "Tack", "Bok",
+        "Klar", "Inte klar", "Figurförteckning", "Noter", "Titelsida",
+    ]),
+    ("zh", &[
+        "目录", "封面", "版权", "献词", "致谢", "书",
+        "已完成", "未完成", "插图目录", "注释", "扉页",
+    ]),
+    ("zh-tw", &[
+        "目錄", "封面", "版權", "獻詞", "致謝", "書",
+        "已完成", "未完成", "插圖目錄", "註釋", "扉頁",
+    ]),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Labels {
+    labels: HashMap<&'static str, String>,
+}
+
+impl Labels {
+    /// Pick the translations for a `book.language` tag (e.g. `pt-BR`),
+    /// falling back to its primary language and then English, with the
+    /// user's `overrides` taking precedence.
+    pub(crate) fn new(language: Option<&str>, overrides: &BTreeMap<String, String>) -> Labels {
+        let translations = language
+            .map(|tag| tag.to_lowercase().replace('_', "-"))
+            .and_then(|tag| {
+                let primary = tag.split('-').next().unwrap_or_default().to_string();
+                translations_for(&tag).or_else(|| translations_for(&primary))
+            })
+            .unwrap_or(TRANSLATIONS[0].1);
+
+        let mut labels: HashMap<_, _> = KEYS
+            .iter()
+            .zip(translations)
+            .map(|(&key, &text)| (key, text.to_string()))
+            .collect();
+
+        for (key, text) in overrides {
+            match KEYS.iter().find(|&&k| k == key.as_str()) {
+                Some(&key) => {
+                    labels.insert(key, text.clone());
+                }
+                None => log::warn!("Ignoring the unknown label \"{}\"", key),
+            }
+        }
+
+        Labels { labels }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> &str {
+        self.labels.get(key).map(String::as_str).unwrap_or_default()
+    }
+
+    /// Every label, for use in templates (e.g. `{{labels.list_of_figures}}`).
+    pub(crate) fn template_context(&self) -> serde_json::Value {
+        let labels: BTreeMap<String, &str> = self
+            .labels
+            .iter()
+            .map(|(key, text)| (key.replace('-', "_"), text.as_str()))
+            .collect();
+
+        json!(labels)
+    }
+}
+
+fn translations_for(language: &str) -> Option<&'static [&'static str]> {
+    TRANSLATIONS
+        .iter()
+        .find(|&&(lang, _)| lang == language)
+        .map(|&(_, translations)| translations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_labels_by_language() {
+        let mut overrides = BTreeMap::new();
+        overrides.insert(String::from(COVER), String::from("Einband"));
+
+        let inputs = vec![
+            (None, "Table of Contents", "Cover"),
+            (Some("fr"), "Table des matières", "Couverture"),
+            (Some("pt-BR"), "Sumário", "Capa"),
+            (Some("zh_TW"), "目錄", "封面"),
+            (Some("tlh"), "Table of Contents", "Cover"),
+        ];
+
+        for (language, toc, cover) in inputs {
+            let labels = Labels::new(language, &BTreeMap::new());
+            assert_eq!(labels.get(TABLE_OF_CONTENTS), toc);
+            assert_eq!(labels.get(COVER), cover);
+        }
+
+        let labels = Labels::new(Some("de"), &overrides);
+        assert_eq!(labels.get(TABLE_OF_CONTENTS), "Inhaltsverzeichnis");
+        assert_eq!(labels.get(COVER), "Einband");
+        assert_eq!(labels.get(NOTES), "Anmerkungen");
+    }
+
+    #[test]
+    fn every_language_translates_every_label() {
+        for &(language, translations) in TRANSLATIONS {
+            assert_eq!(translations.len(), KEYS.len(), "{}", language);
+        }
+    }
+
+    #[test]
+    fn templates_can_use_the_labels() {
+        let labels = Labels::new(Some("fr"), &BTreeMap::new());
+
+        let got = labels.template_context();
+
+        assert_eq!(got["list_of_figures"], "Table des figures");
+        assert_eq!(got["not_completed"], "Non terminé");
+    }
+}
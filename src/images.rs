@@ -0,0 +1,156 @@
+//! Downscaling and recompressing images as they're embedded.
+
+use failure::{Error, ResultExt};
+use glob::Pattern;
+use image::imageops::FilterType;
+use image::{GenericImageView, ImageOutputFormat};
+use mime_guess::Mime;
+use std::path::Path;
+
+use crate::config::Config;
+
+/// How a particular image should be processed.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub(crate) struct ImageSettings {
+    /// The JPEG quality to re-encode with.
+    pub(crate) quality: Option<u8>,
+    /// Images wider than this are scaled down.
+    pub(crate) max_width: Option<u32>,
+    /// Never apply lossy compression.
+    pub(crate) lossless: bool,
+    /// Embed the image exactly as it is on disk.
+    pub(crate) keep_original: bool,
+}
+
+impl ImageSettings {
+    /// Figure out the settings for an image (relative to the `src/`
+    /// directory), starting with the global settings and applying the first
+    /// matching `image-rules` entry.
+    pub(crate) fn for_image(path: &Path, config: &Config) -> Result<ImageSettings, Error> {
+        let mut settings = ImageSettings {
+            quality: config.image_quality,
+            max_width: config.max_image_width,
+            lossless: false,
+            keep_original: false,
+        };
+
+        for rule in &config.image_rules {
+            let pattern = Pattern::new(&rule.pattern)
+                .with_context(|_| format!("Invalid image-rules pattern, \"{}\"", rule.pattern))?;
+
+            if pattern.matches_path(path) {
+                log::trace!(
+                    "{} matched the image rule \"{}\"",
+                    path.display(),
+                    rule.pattern
+                );
+                if rule.quality.is_some() {
+                    settings.quality = rule.quality;
+                }
+                if rule.max_width.is_some() {
+                    settings.max_width = rule.max_width;
+                }
+                settings.lossless = rule.lossless;
+                settings.keep_original = rule.keep_original;
+                break;
+            }
+        }
+
+        Ok(settings)
+    }
+
+    fn is_noop(&self) -> bool {
+        self.keep_original || (self.quality.is_none() && self.max_width.is_none())
+    }
+}
+
+/// Apply the settings to an image, returning the original bytes if nothing
+/// needs to change. Only JPEG and PNG images are processed, anything else
+/// (e.g. SVG diagrams) is always left alone.
+pub(crate) fn process(
+    data: Vec<u8>,
+    mime: &Mime,
+    settings: &ImageSettings,
+) -> Result<Vec<u8>, Error> {
+    let is_jpeg = mime.essence_str() == "image/jpeg";
+    let is_png = mime.essence_str() == "image/png";
+
+    if settings.is_noop() || !(is_jpeg || is_png) {
+        return Ok(data);
+    }
+
+    let mut img = image::load_from_memory(&data).context("Unable to decode the image")?;
+    let mut resized = false;
+
+    if let Some(max_width) = settings.max_width {
+        if img.width() > max_width {
+            log::trace!(
+                "Scaling image down from {}px to {}px wide",
+                img.width(),
+                max_width
+            );
+            img = img.resize(max_width, img.height(), FilterType::Lanczos3);
+            resized = true;
+        }
+    }
+
+    let format = if is_jpeg {
+        match (settings.lossless, settings.quality) {
+            (true, _) if resized => ImageOutputFormat::Jpeg(100),
+            (false, Some(quality)) => ImageOutputFormat::Jpeg(quality),
+            (false, None) if resized => ImageOutputFormat::Jpeg(90),
+            _ => return Ok(data),
+        }
+    } else if resized {
+        ImageOutputFormat::Png
+    } else {
+        return Ok(data);
+    };
+
+    let mut processed = Vec::new();
+    img.write_to(&mut processed, format)
+        .context("Unable to encode the image")?;
+
+    // recompressing doesn't always help
+    if processed.len() < data.len() || resized {
+        Ok(processed)
+    } else {
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ImageRule;
+
+    #[test]
+    fn the_first_matching_rule_wins() {
+        let mut config = Config::default();
+        config.image_quality = Some(80);
+        config.image_rules = vec![
+            ImageRule {
+                pattern: String::from("photos/*.jpg"),
+                quality: Some(70),
+                ..Default::default()
+            },
+            ImageRule {
+                pattern: String::from("**/*.jpg"),
+                lossless: true,
+                ..Default::default()
+            },
+        ];
+
+        let photo = ImageSettings::for_image(Path::new("photos/cat.jpg"), &config).unwrap();
+        assert_eq!(photo.quality, Some(70));
+        assert!(!photo.lossless);
+
+        let other = ImageSettings::for_image(Path::new("img/cat.jpg"), &config).unwrap();
+        assert_eq!(other.quality, Some(80));
+        assert!(other.lossless);
+
+        let png = ImageSettings::for_image(Path::new("img/cat.png"), &config).unwrap();
+        assert_eq!(png.quality, Some(80));
+        assert!(!png.lossless);
+    }
+}
@@ -0,0 +1,84 @@
+//! Pronunciation lexicons ([PLS]), which text-to-speech reading systems use
+//! to pronounce project names and jargon properly.
+//!
+//! [PLS]: https://www.w3.org/TR/pronunciation-lexicon/
+
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::utils;
+
+/// The media type of a PLS document.
+pub(crate) const MEDIA_TYPE: &str = "application/pls+xml";
+
+/// The paths (relative to the `OEBPS/` root) of every lexicon which will be
+/// embedded, the user's own lexicon first.
+pub(crate) fn lexicon_names(config: &Config) -> Vec<String> {
+    let mut names = Vec::new();
+
+    if let Some(ref lexicon) = config.pronunciation_lexicon {
+        let file_name = lexicon
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("lexicon.pls");
+        names.push(format!("lexicons/{}", file_name));
+    }
+    if !config.pronunciations.is_empty() {
+        let mut name = String::from("lexicons/pronunciations.pls");
+        if names.contains(&name) {
+            name = String::from("lexicons/generated-pronunciations.pls");
+        }
+        names.push(name);
+    }
+
+    names
+}
+
+/// Write a PLS document using IPA for the `pronunciations` (keyed by the word
+/// they're for).
+pub(crate) fn generate_pls(pronunciations: &BTreeMap<String, String>, language: &str) -> String {
+    let mut pls = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <lexicon version=\"1.0\" xmlns=\"http://www.w3.org/2005/01/pronunciation-lexicon\" \
+         alphabet=\"ipa\" xml:lang=\"{}\">\n",
+        utils::escape_html(language)
+    );
+
+    for (grapheme, phoneme) in pronunciations {
+        pls.push_str(&format!(
+            "  <lexeme>\n    <grapheme>{}</grapheme>\n    <phoneme>{}</phoneme>\n  </lexeme>\n",
+            utils::escape_html(grapheme),
+            utils::escape_html(phoneme)
+        ));
+    }
+
+    pls.push_str("</lexicon>\n");
+    pls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_a_lexicon() {
+        let mut pronunciations = BTreeMap::new();
+        pronunciations.insert(String::from("mdBook"), String::from("ˌɛmˈdiːbʊk"));
+        pronunciations.insert(
+            String::from("Rust & Cargo"),
+            String::from("rʌst ənd ˈkɑːɡoʊ"),
+        );
+        let should_be = concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<lexicon version=\"1.0\" xmlns=\"http://www.w3.org/2005/01/pronunciation-lexicon\" ",
+            "alphabet=\"ipa\" xml:lang=\"en\">\n",
+            "  <lexeme>\n    <grapheme>Rust &amp; Cargo</grapheme>\n",
+            "    <phoneme>rʌst ənd ˈkɑːɡoʊ</phoneme>\n  </lexeme>\n",
+            "  <lexeme>\n    <grapheme>mdBook</grapheme>\n",
+            "    <phoneme>ˌɛmˈdiːbʊk</phoneme>\n  </lexeme>\n",
+            "</lexicon>\n",
+        );
+
+        assert_eq!(generate_pls(&pronunciations, "en"), should_be);
+    }
+}
@@ -19,20 +19,48 @@ extern crate serde_derive;
 #[macro_use]
 extern crate lazy_static;
 
-use failure::Error;
+use failure::{Error, Fail, ResultExt};
+use mdbook::book::BookItem;
 use mdbook::config::Config as MdConfig;
 use mdbook::renderer::RenderContext;
 use semver::{Version, VersionReq};
+use std::collections::BTreeMap;
 use std::fs::{create_dir_all, File};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 
+mod check;
 mod config;
+mod cover;
+mod fonts;
 mod generator;
+mod hyphenation;
+mod i18n;
+mod images;
+mod lexicon;
+mod links;
+mod logging;
+mod markdown;
+mod package;
+mod report;
 mod resources;
+mod slug;
+mod stats;
 mod utils;
+mod web_only;
 
-pub use crate::config::Config;
+pub use crate::check::{CheckReport, Problem, Severity};
+pub use crate::config::{
+    AdditionalResource, Config, CoverConfig, ImageLinks, ImageRule, MarkdownOptions,
+    ParagraphStyle, SpinePage, Typography, WebOnly,
+};
 pub use crate::generator::Generator;
+use crate::generator::RenderCache;
+pub use crate::links::BrokenLink;
+pub use crate::logging::init_logger;
+use crate::logging::LogFile;
+pub use crate::report::{AssetReport, ChapterReport, Report, SpineReport, TocEntry};
+pub use crate::stats::{Phase, Stats};
 
 /// The default stylesheet used to make the rendered document pretty.
 pub const DEFAULT_CSS: &str = include_str!("master.css");
@@ -42,7 +70,8 @@ pub const MDBOOK_VERSION: &str = mdbook::MDBOOK_VERSION;
 
 #[derive(Debug, Clone, PartialEq, Fail)]
 #[fail(
-    display = "Incompatible mdbook version, expected {} but got {}",
+    display = "Incompatible mdbook version, expected {} but got {} (use `--force` or set \
+               `output.epub.ignore-version-mismatch = true` to try anyway)",
     expected, got
 )]
 struct IncompatibleMdbookVersion {
@@ -62,14 +91,105 @@ fn version_check(ctx: &RenderContext) -> Result<(), Error> {
             got: ctx.version.clone(),
         };
 
-        Err(Error::from(e))
+        if Config::from_render_context(ctx)?.ignore_version_mismatch {
+            log::warn!("{}", e);
+            Ok(())
+        } else {
+            Err(Error::from(e))
+        }
     } else {
         Ok(())
     }
 }
 
+/// Read the `RenderContext` passed to us by `mdbook`.
+///
+/// If the context can't be deserialized and it came from a different version
+/// of `mdbook`, the error will say so instead of just pointing at the
+/// offending field. When `force` is set (or the book sets
+/// `ignore-version-mismatch`), any book items this version of `mdbook` doesn't
+/// understand are skipped with a warning instead.
+pub fn load_render_context<R: Read>(reader: R, force: bool) -> Result<RenderContext, Error> {
+    let mut value: serde_json::Value =
+        serde_json::from_reader(reader).context("Unable to parse RenderContext")?;
+    let version = value
+        .get("version")
+        .and_then(|v| v.as_str())
+        .unwrap_or("unknown")
+        .to_string();
+    let force = force
+        || value
+            .pointer("/config/output/epub/ignore-version-mismatch")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+    let e = match serde_json::from_value(value.clone()) {
+        Ok(ctx) => return Ok(ctx),
+        Err(e) => e,
+    };
+
+    let compatible = match (Version::parse(&version), VersionReq::parse(MDBOOK_VERSION)) {
+        (Ok(provided), Ok(required)) => required.matches(&provided),
+        _ => false,
+    };
+    if compatible {
+        return Err(Error::from(e.context("Unable to parse RenderContext")));
+    }
+
+    let mismatch = IncompatibleMdbookVersion {
+        expected: MDBOOK_VERSION.to_string(),
+        got: version,
+    };
+    if !force {
+        return Err(Error::from(e.context(mismatch.to_string())));
+    }
+
+    log::warn!("{}", mismatch);
+    if let Some(sections) = value.pointer_mut("/book/sections") {
+        skip_unknown_items(sections);
+    }
+
+    let ctx = serde_json::from_value(value).context(mismatch.to_string())?;
+    Ok(ctx)
+}
+
+/// Remove every book item (recursively) which can't be deserialized as a
+/// `BookItem`, e.g. part titles and draft chapters from newer versions of
+/// `mdbook`.
+fn skip_unknown_items(items: &mut serde_json::Value) {
+    let items = match items.as_array_mut() {
+        Some(items) => items,
+        None => return,
+    };
+
+    for item in items.iter_mut() {
+        if let Some(sub_items) = item.pointer_mut("/Chapter/sub_items") {
+            skip_unknown_items(sub_items);
+        }
+    }
+
+    items.retain(
+        |item| match serde_json::from_value::<BookItem>(item.clone()) {
+            Ok(_) => true,
+            Err(e) => {
+                log::warn!(
+                    "Skipping a book item this version of mdbook can't read: {}",
+                    e
+                );
+                false
+            }
+        },
+    );
+}
+
 /// Generate an `EPUB` version of the provided book.
 pub fn generate(ctx: &RenderContext) -> Result<(), Error> {
+    generate_with_stats(ctx).map(|_| ())
+}
+
+/// Generate the book (and any profiles), returning how long each phase of
+/// generating each book took.
+pub fn generate_with_stats(ctx: &RenderContext) -> Result<Vec<(PathBuf, Stats)>, Error> {
     log::info!("Starting the EPUB generator");
     version_check(ctx)?;
 
@@ -84,12 +204,121 @@ pub fn generate(ctx: &RenderContext) -> Result<(), Error> {
         create_dir_all(&ctx.destination)?;
     }
 
-    let f = File::create(&outfile)?;
-    Generator::new(ctx)?.generate(f)?;
+    let config = Config::from_render_context(ctx)?;
+    let log_file = if config.log_file {
+        Some(LogFile::create(&outfile.with_extension("log"))?)
+    } else {
+        None
+    };
+
+    let result = generate_all(ctx, config, outfile);
+    if let (Some(log_file), Err(e)) = (&log_file, &result) {
+        log_file.record_failure(e);
+    }
+
+    result
+}
+
+/// Generate the book, followed by each of its profiles.
+fn generate_all(
+    ctx: &RenderContext,
+    config: Config,
+    outfile: PathBuf,
+) -> Result<Vec<(PathBuf, Stats)>, Error> {
+    let cache = RenderCache::default();
+    let stats = generate_book(ctx, config.clone(), &outfile, &cache)?;
+    let mut all_stats = vec![(outfile, stats)];
+
+    // every profile is rendered from the same chapters, so they can share
+    // the markdown rendering
+    for profile in config.profiles.keys() {
+        let outfile = profile_filename(&ctx.destination, &ctx.config, profile);
+        log::info!("Generating the \"{}\" profile", profile);
+        log::trace!("Output File: {}", outfile.display());
+
+        let profile_config = Config::for_profile(ctx, profile)?;
+        let stats = generate_book(ctx, profile_config, &outfile, &cache)?;
+        all_stats.push((outfile, stats));
+    }
+
+    Ok(all_stats)
+}
+
+fn generate_book(
+    ctx: &RenderContext,
+    config: Config,
+    outfile: &Path,
+    cache: &RenderCache,
+) -> Result<Stats, Error> {
+    let checksums = config.checksums;
+
+    let f = File::create(outfile)?;
+    let mut generator = Generator::with_config(ctx, config)?.with_render_cache(cache.clone());
+    generator.generate(f)?;
+
+    if checksums {
+        write_checksums(outfile, generator.checksums())?;
+    }
+
+    Ok(generator.stats().clone())
+}
+
+/// Look for problems with the book (missing assets, broken links, invalid
+/// XHTML, missing metadata, and so on) without generating anything.
+pub fn check(ctx: &RenderContext) -> CheckReport {
+    log::info!("Checking the book");
+    CheckReport::from_render_context(ctx)
+}
+
+/// Go through the motions of generating a book (traversing chapters, finding
+/// assets, and checking links) without writing anything to disk.
+pub fn dry_run(ctx: &RenderContext) -> Result<Report, Error> {
+    log::info!("Inspecting the book (dry run)");
+    version_check(ctx)?;
+
+    Report::from_render_context(ctx)
+}
+
+/// Generate the book in memory and report the spine and table of contents it
+/// ended up with, to help debug complex `SUMMARY.md` structures.
+pub fn spine_report(ctx: &RenderContext) -> Result<SpineReport, Error> {
+    log::info!("Inspecting the book's spine");
+    version_check(ctx)?;
+
+    let mut generator = Generator::new(ctx)?;
+    generator.generate(io::sink())?;
+
+    Ok(generator.spine_report())
+}
+
+/// Write a sidecar file next to the book listing the SHA-256 checksum of each
+/// embedded file, in the same format used by `sha256sum`.
+fn write_checksums(outfile: &Path, checksums: &BTreeMap<String, String>) -> Result<(), Error> {
+    let mut filename = outfile.as_os_str().to_owned();
+    filename.push(".sha256");
+    let filename = PathBuf::from(filename);
+    log::debug!("Writing checksums to {}", filename.display());
+
+    let mut f = File::create(&filename)?;
+    for (path, digest) in checksums {
+        writeln!(f, "{}  {}", digest, path)?;
+    }
 
     Ok(())
 }
 
+/// Calculate the output filename for one of the `output.epub.profiles`
+/// (e.g. `book-lite.epub`).
+pub fn profile_filename(dest: &Path, config: &MdConfig, profile: &str) -> PathBuf {
+    let book = output_filename(dest, config);
+    let stem = book
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    book.with_file_name(format!("{}-{}.epub", stem, profile))
+}
+
 /// Calculate the output filename using the `mdbook` config.
 pub fn output_filename(dest: &Path, config: &MdConfig) -> PathBuf {
     match config.book.title {
@@ -0,0 +1,159 @@
+//! Checking intra-book links.
+
+use failure::{Error, ResultExt};
+use mdbook::book::BookItem;
+use mdbook::renderer::RenderContext;
+use pulldown_cmark::{Event, Parser, Tag};
+use std::path::{Component, Path, PathBuf};
+
+use crate::markdown;
+
+/// A link which doesn't point at a chapter or file in the book.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BrokenLink {
+    /// The chapter containing the link, relative to the `src/` directory.
+    pub chapter: PathBuf,
+    /// The link's destination, as written.
+    pub link: String,
+}
+
+/// Look through every chapter for relative links which don't resolve to
+/// another chapter or a file in the `src/` directory.
+pub(crate) fn broken_links(ctx: &RenderContext) -> Result<Vec<BrokenLink>, Error> {
+    let src_dir = ctx
+        .root
+        .join(&ctx.config.book.src)
+        .canonicalize()
+        .context("Unable to canonicalize the src directory")?;
+
+    let chapters: Vec<PathBuf> = ctx
+        .book
+        .iter()
+        .filter_map(|item| match *item {
+            BookItem::Chapter(ref ch) => Some(ch.path.with_extension("html")),
+            _ => None,
+        })
+        .collect();
+
+    let mut broken = Vec::new();
+
+    for item in ctx.book.iter() {
+        if let BookItem::Chapter(ref ch) = *item {
+            let parent = ch.path.parent().unwrap_or_else(|| Path::new(""));
+
+            let content = markdown::conditional_content(&ch.content);
+            for event in Parser::new(&content) {
+                let dest = match event {
+                    Event::Start(Tag::Link(_, dest, _)) => dest,
+                    _ => continue,
+                };
+
+                if !is_relative(&dest) {
+                    continue;
+                }
+
+                let target = dest.split('#').next().unwrap_or_default();
+                let target = normalize(&parent.join(target));
+
+                let found = match target.extension().and_then(|e| e.to_str()) {
+                    Some("md") | Some("html") => chapters.contains(&target.with_extension("html")),
+                    _ => src_dir.join(&target).exists(),
+                };
+
+                if !found {
+                    log::debug!("Broken link to \"{}\" in {}", dest, ch.path.display());
+                    broken.push(BrokenLink {
+                        chapter: ch.path.clone(),
+                        link: dest.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(broken)
+}
+
+/// Is this a link to something inside the book (as opposed to a URL or an
+/// anchor in the same page)?
+pub(crate) fn is_relative(link: &str) -> bool {
+    !(link.is_empty()
+        || link.starts_with('#')
+        || link.starts_with('/')
+        || link.contains("://")
+        || link.starts_with("mailto:"))
+}
+
+/// Lexically resolve any `.` and `..` components in a path.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+
+    normalized
+}
+
+/// The link from a file in `from_dir` to `to`, where both are relative to the
+/// same root.
+pub(crate) fn relative_path(from_dir: &Path, to: &Path) -> String {
+    let from: Vec<_> = from_dir.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut parts = vec![String::from(".."); from.len() - common];
+    parts.extend(
+        to[common..]
+            .iter()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned()),
+    );
+
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_relative_paths() {
+        let inputs = vec![
+            ("chapter_1.md", "chapter_1.md"),
+            ("./a/../b/c.md", "b/c.md"),
+            ("a/./b/../../c.png", "c.png"),
+        ];
+
+        for (src, should_be) in inputs {
+            assert_eq!(normalize(Path::new(src)), PathBuf::from(should_be));
+        }
+    }
+
+    #[test]
+    fn link_between_directories() {
+        let inputs = vec![
+            ("", "img-1a2b3c4d.png", "img-1a2b3c4d.png"),
+            ("a", "a/img.png", "img.png"),
+            ("a/b", "c/img.png", "../../c/img.png"),
+        ];
+
+        for (from, to, should_be) in inputs {
+            assert_eq!(relative_path(Path::new(from), Path::new(to)), should_be);
+        }
+    }
+
+    #[test]
+    fn only_internal_links_are_checked() {
+        assert!(is_relative("chapter_1.md#heading"));
+        assert!(is_relative("../images/logo.png"));
+        assert!(!is_relative("#heading"));
+        assert!(!is_relative("https://rust-lang.org/"));
+        assert!(!is_relative("mailto:someone@example.com"));
+    }
+}
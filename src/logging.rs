@@ -0,0 +1,224 @@
+//! Logging which is tagged with where in the build a message came from (the
+//! phase, chapter, asset, ...), and can be copied into a log file so failures
+//! can be diagnosed after the fact (e.g. on CI).
+
+use chrono::Utc;
+use failure::{Error, ResultExt};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// How much detail from this crate goes into the log file, regardless of
+/// `RUST_LOG`. Everything else only contributes warnings and errors.
+const LOG_FILE_LEVEL: LevelFilter = LevelFilter::Debug;
+
+thread_local! {
+    /// The fields each message logged on this thread is tagged with.
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = RefCell::new(Vec::new());
+}
+
+lazy_static! {
+    static ref LOG_FILE: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+}
+
+/// Install a logger which prints to stderr (filtered using `RUST_LOG`, the
+/// same as `env_logger`), and which can also write to the log file.
+pub fn init_logger() {
+    let stderr = env_logger::Builder::from_default_env().build();
+    let level = stderr.filter();
+
+    if log::set_boxed_logger(Box::new(BuildLogger { stderr })).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Tag every message logged on this thread with `key=value`, until the
+/// returned guard is dropped.
+pub(crate) fn scope<V: Display>(key: &'static str, value: V) -> Scope {
+    FIELDS.with(|fields| fields.borrow_mut().push((key, value.to_string())));
+    Scope { _private: () }
+}
+
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct Scope {
+    _private: (),
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        FIELDS.with(|fields| {
+            fields.borrow_mut().pop();
+        });
+    }
+}
+
+/// Copies everything logged into a file until it's dropped.
+#[derive(Debug)]
+pub(crate) struct LogFile {
+    previous_level: LevelFilter,
+}
+
+impl LogFile {
+    pub(crate) fn create(path: &Path) -> Result<LogFile, Error> {
+        let file = File::create(path)
+            .with_context(|_| format!("Unable to create the log file, {}", path.display()))?;
+
+        if let Ok(mut log_file) = LOG_FILE.lock() {
+            *log_file = Some(BufWriter::new(file));
+        }
+
+        let previous_level = log::max_level();
+        log::set_max_level(previous_level.max(LOG_FILE_LEVEL));
+
+        Ok(LogFile { previous_level })
+    }
+
+    /// Write the error which stopped the build to the log file, without
+    /// printing it twice.
+    pub(crate) fn record_failure(&self, e: &Error) {
+        let causes: Vec<String> = e.iter_chain().map(|cause| cause.to_string()).collect();
+        write_line(format_args!("{} ERROR {}", timestamp(), causes.join(": ")));
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        if let Ok(mut log_file) = LOG_FILE.lock() {
+            if let Some(mut file) = log_file.take() {
+                let _ = file.flush();
+            }
+        }
+
+        log::set_max_level(self.previous_level);
+    }
+}
+
+struct BuildLogger {
+    stderr: env_logger::Logger,
+}
+
+impl BuildLogger {
+    fn goes_in_log_file(metadata: &Metadata<'_>) -> bool {
+        let level = if metadata.target().starts_with("mdbook_epub") {
+            LOG_FILE_LEVEL
+        } else {
+            LevelFilter::Warn
+        };
+
+        metadata.level() <= level && LOG_FILE.lock().map_or(false, |f| f.is_some())
+    }
+}
+
+impl Log for BuildLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.stderr.enabled(metadata) || BuildLogger::goes_in_log_file(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        let fields = Fields::current();
+
+        if self.stderr.matches(record) {
+            self.stderr.log(
+                &Record::builder()
+                    .args(format_args!("{}{}", fields, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            );
+        }
+
+        if BuildLogger::goes_in_log_file(record.metadata()) {
+            write_line(format_args!(
+                "{} {:<5} {}: {}{}",
+                timestamp(),
+                record.level(),
+                record.target(),
+                fields,
+                record.args()
+            ));
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+
+        if let Ok(mut log_file) = LOG_FILE.lock() {
+            if let Some(ref mut file) = *log_file {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for BuildLogger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BuildLogger").finish()
+    }
+}
+
+fn write_line(line: fmt::Arguments<'_>) {
+    if let Ok(mut log_file) = LOG_FILE.lock() {
+        if let Some(ref mut file) = *log_file {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+fn timestamp() -> String {
+    Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// The fields a message is tagged with, written as `[key="value" ...] `.
+#[derive(Debug, Clone)]
+struct Fields(Vec<(&'static str, String)>);
+
+impl Fields {
+    fn current() -> Fields {
+        FIELDS.with(|fields| Fields(fields.borrow().clone()))
+    }
+}
+
+impl Display for Fields {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        let fields: Vec<String> = self
+            .0
+            .iter()
+            .map(|(key, value)| format!("{}={:?}", key, value))
+            .collect();
+
+        write!(f, "[{}] ", fields.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_tag_messages() {
+        assert_eq!(Fields::current().to_string(), "");
+
+        {
+            let _phase = scope("phase", "render chapters");
+            let _chapter = scope("chapter", "intro/index.md");
+
+            assert_eq!(
+                Fields::current().to_string(),
+                "[phase=\"render chapters\" chapter=\"intro/index.md\"] "
+            );
+        }
+
+        assert_eq!(Fields::current().to_string(), "");
+    }
+}
@@ -0,0 +1,452 @@
+//! Rendering chapters from markdown to HTML.
+//!
+//! This mirrors what `mdbook::utils::render_markdown()` does for the HTML
+//! renderer, except the `pulldown-cmark` extensions can be toggled from the
+//! `[output.epub.markdown]` table. On top of that, headings may be given
+//! attributes (`# Heading {#custom-id .class}`), links to other chapters
+//! are pointed at the chapter's XHTML file, and `{漢字|かんじ}` can optionally
+//! be used as a shorthand for ruby annotations.
+//!
+//! Content can also be marked as web-only or EPUB-only with HTML comments:
+//!
+//! ```markdown
+//! <!-- epub:skip -->
+//! This is only shown in the HTML version.
+//! <!-- /epub:skip -->
+//!
+//! <!-- epub:only
+//! And this is only shown in the EPUB.
+//! -->
+//! ```
+
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::ops::Range;
+
+use crate::config::MarkdownOptions;
+use crate::links;
+use crate::utils::escape_html;
+
+/// Render a chapter's markdown to HTML.
+pub(crate) fn render(text: &str, options: &MarkdownOptions) -> String {
+    let mut opts = Options::empty();
+    if options.tables {
+        opts.insert(Options::ENABLE_TABLES);
+    }
+    if options.footnotes {
+        opts.insert(Options::ENABLE_FOOTNOTES);
+    }
+    if options.strikethrough {
+        opts.insert(Options::ENABLE_STRIKETHROUGH);
+    }
+    if options.tasklists {
+        opts.insert(Options::ENABLE_TASKLISTS);
+    }
+
+    let curly_quotes = options.curly_quotes.unwrap_or(false);
+    let mut in_code_block = false;
+
+    let text = conditional_content(text);
+
+    let events = Parser::new_ext(&text, opts).map(|event| match event {
+        Event::Start(Tag::CodeBlock(info)) => {
+            in_code_block = true;
+            Event::Start(Tag::CodeBlock(clean_codeblock_info(&info)))
+        }
+        Event::End(Tag::CodeBlock(info)) => {
+            in_code_block = false;
+            Event::End(Tag::CodeBlock(info))
+        }
+        Event::Text(ref text) if curly_quotes && !in_code_block => {
+            Event::Text(CowStr::from(convert_quotes_to_curly(text)))
+        }
+        Event::Start(Tag::Link(ty, dest, title)) => {
+            Event::Start(Tag::Link(ty, rewrite_chapter_link(dest), title))
+        }
+        other => other,
+    });
+    let mut events = merge_text(events.collect());
+    if options.ruby {
+        events = apply_ruby(events);
+    }
+    let events = apply_heading_attributes(events);
+
+    let mut rendered = String::with_capacity(text.len() * 3 / 2);
+    html::push_html(&mut rendered, events.into_iter());
+    rendered
+}
+
+/// Remove anything wrapped in `<!-- epub:skip -->` markers and unwrap the
+/// contents of `<!-- epub:only ... -->` comments. Markers inside code are
+/// examples of the syntax, so they're left alone.
+pub(crate) fn conditional_content(text: &str) -> Cow<'_, str> {
+    lazy_static! {
+        static ref CONDITIONAL: Regex = Regex::new(concat!(
+            r"(?s)<!--\s*epub:skip\s*-->.*?<!--\s*/epub:skip\s*-->",
+            r"|<!--\s*epub:only\b(?P<only>.*?)-->",
+        ))
+        .unwrap();
+    }
+
+    if !text.contains("epub:") {
+        return Cow::Borrowed(text);
+    }
+
+    let code = code_ranges(text);
+    let in_code = |offset: usize| code.iter().any(|range| range.contains(&offset));
+
+    CONDITIONAL.replace_all(text, |caps: &Captures<'_>| {
+        let whole = caps.get(0).unwrap();
+        if in_code(whole.start()) || in_code(whole.end() - 1) {
+            whole.as_str().to_string()
+        } else {
+            caps.name("only")
+                .map(|m| m.as_str())
+                .unwrap_or_default()
+                .to_string()
+        }
+    })
+}
+
+/// Where the code blocks and inline code are in a chapter's markdown.
+fn code_ranges(text: &str) -> Vec<Range<usize>> {
+    Parser::new(text)
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Start(Tag::CodeBlock(_)) | Event::Code(_) => Some(range),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `pulldown-cmark` may split a run of text into several events, join them
+/// back together so the later passes can see the whole thing.
+fn merge_text(events: Vec<Event<'_>>) -> Vec<Event<'_>> {
+    let mut out: Vec<Event<'_>> = Vec::with_capacity(events.len());
+
+    for event in events {
+        if let Event::Text(ref text) = event {
+            if let Some(Event::Text(previous)) = out.last_mut() {
+                *previous = CowStr::from(format!("{}{}", previous, text));
+                continue;
+            }
+        }
+
+        out.push(event);
+    }
+
+    out
+}
+
+/// Expand the `{base|annotation}` shorthand into `<ruby>` elements. If the
+/// annotation is split by `|` into as many parts as there are characters in
+/// the base, each character gets its own annotation.
+fn apply_ruby(events: Vec<Event<'_>>) -> Vec<Event<'_>> {
+    lazy_static! {
+        static ref RUBY: Regex = Regex::new(r"\{([^{}|]+)\|([^{}]+)\}").unwrap();
+    }
+
+    let mut out = Vec::with_capacity(events.len());
+    let mut in_code_block = false;
+
+    for event in events {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                out.push(event);
+            }
+            Event::End(Tag::CodeBlock(_)) => {
+                in_code_block = false;
+                out.push(event);
+            }
+            Event::Text(ref text) if !in_code_block && RUBY.is_match(text) => {
+                let mut last = 0;
+
+                for caps in RUBY.captures_iter(text) {
+                    let whole = caps.get(0).unwrap();
+                    if whole.start() > last {
+                        let before = text[last..whole.start()].to_string();
+                        out.push(Event::Text(CowStr::from(before)));
+                    }
+                    out.push(Event::Html(CowStr::from(ruby(&caps[1], &caps[2]))));
+                    last = whole.end();
+                }
+
+                if last < text.len() {
+                    out.push(Event::Text(CowStr::from(text[last..].to_string())));
+                }
+            }
+            other => out.push(other),
+        }
+    }
+
+    out
+}
+
+fn ruby(base: &str, annotation: &str) -> String {
+    let bases: Vec<char> = base.chars().collect();
+    let annotations: Vec<&str> = annotation.split('|').collect();
+
+    let per_character = annotations.len() > 1 && annotations.len() == bases.len();
+
+    let pairs: Vec<(String, &str)> = if per_character {
+        bases
+            .iter()
+            .map(|c| c.to_string())
+            .zip(annotations)
+            .collect()
+    } else {
+        vec![(base.to_string(), annotation)]
+    };
+
+    let mut html = String::from("<ruby>");
+    for (base, annotation) in pairs {
+        html.push_str(&format!(
+            "{}<rp>(</rp><rt>{}</rt><rp>)</rp>",
+            escape_html(&base),
+            escape_html(annotation)
+        ));
+    }
+    html.push_str("</ruby>");
+
+    html
+}
+
+/// Links to other chapters are written against their markdown files, but
+/// inside the book they become `*.html`.
+fn rewrite_chapter_link(dest: CowStr<'_>) -> CowStr<'_> {
+    if !links::is_relative(&dest) {
+        return dest;
+    }
+
+    let mut parts = dest.splitn(2, '#');
+    let path = parts.next().unwrap_or_default();
+    let fragment = parts.next();
+
+    if !path.ends_with(".md") {
+        return dest;
+    }
+
+    let mut rewritten = format!("{}.html", &path[..path.len() - 3]);
+    if let Some(fragment) = fragment {
+        rewritten.push('#');
+        rewritten.push_str(fragment);
+    }
+
+    CowStr::from(rewritten)
+}
+
+/// Look for headings ending in `{#id .class}` and turn them into the
+/// corresponding `id` and `class` attributes.
+fn apply_heading_attributes(events: Vec<Event<'_>>) -> Vec<Event<'_>> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut heading: Option<Vec<Event<'_>>> = None;
+
+    for event in events {
+        match event {
+            Event::Start(Tag::Heading(_)) => heading = Some(Vec::new()),
+            Event::End(Tag::Heading(level)) => {
+                let mut inner = heading.take().unwrap_or_default();
+
+                match take_attributes(&mut inner) {
+                    Some(attributes) => {
+                        out.push(Event::Html(CowStr::from(format!(
+                            "<h{}{}>",
+                            level, attributes
+                        ))));
+                        out.extend(inner);
+                        out.push(Event::Html(CowStr::from(format!("</h{}>\n", level))));
+                    }
+                    None => {
+                        out.push(Event::Start(Tag::Heading(level)));
+                        out.extend(inner);
+                        out.push(Event::End(Tag::Heading(level)));
+                    }
+                }
+            }
+            other => match heading {
+                Some(ref mut inner) => inner.push(other),
+                None => out.push(other),
+            },
+        }
+    }
+
+    out
+}
+
+/// If a heading's text ends with an attribute block, strip it off and return
+/// the equivalent HTML attributes.
+fn take_attributes(inner: &mut Vec<Event<'_>>) -> Option<String> {
+    let text = match inner.last() {
+        Some(Event::Text(text)) => text.trim_end().to_string(),
+        _ => return None,
+    };
+
+    if !text.ends_with('}') {
+        return None;
+    }
+    let start = text.rfind('{')?;
+
+    let mut id = None;
+    let mut classes = Vec::new();
+    for token in text[start + 1..text.len() - 1].split_whitespace() {
+        if token.starts_with('#') && token.len() > 1 {
+            id = Some(&token[1..]);
+        } else if token.starts_with('.') && token.len() > 1 {
+            classes.push(&token[1..]);
+        } else {
+            return None;
+        }
+    }
+
+    let mut attributes = String::new();
+    if let Some(id) = id {
+        attributes.push_str(&format!(" id=\"{}\"", escape_html(id)));
+    }
+    if !classes.is_empty() {
+        attributes.push_str(&format!(" class=\"{}\"", escape_html(&classes.join(" "))));
+    }
+    if attributes.is_empty() {
+        return None;
+    }
+
+    let remaining = text[..start].trim_end().to_string();
+    inner.pop();
+    if !remaining.is_empty() {
+        inner.push(Event::Text(CowStr::from(remaining)));
+    }
+
+    Some(attributes)
+}
+
+/// `mdbook` lets people annotate code blocks with things like
+/// `rust,editable`, we don't care about the whitespace in there.
+fn clean_codeblock_info(info: &str) -> CowStr<'static> {
+    let info: String = info.chars().filter(|c| !c.is_whitespace()).collect();
+    CowStr::from(info)
+}
+
+fn convert_quotes_to_curly(original: &str) -> String {
+    // the start of a text node counts as whitespace
+    let mut preceded_by_whitespace = true;
+
+    original
+        .chars()
+        .map(|c| {
+            let converted = match c {
+                '\'' if preceded_by_whitespace => '\u{2018}',
+                '\'' => '\u{2019}',
+                '"' if preceded_by_whitespace => '\u{201c}',
+                '"' => '\u{201d}',
+                _ => c,
+            };
+            preceded_by_whitespace = c.is_whitespace();
+            converted
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions_can_be_turned_off() {
+        let src = "~~gone~~\n\n| a |\n|---|\n| b |\n";
+        let mut options = MarkdownOptions::default();
+
+        let got = render(src, &options);
+        assert!(got.contains("<del>gone</del>"));
+        assert!(got.contains("<table>"));
+
+        options.strikethrough = false;
+        options.tables = false;
+        let got = render(src, &options);
+        assert!(!got.contains("<del>"));
+        assert!(!got.contains("<table>"));
+    }
+
+    #[test]
+    fn heading_attributes_become_html_attributes() {
+        let src = "# Chapter 1 {#intro .unnumbered .big}\n\n## Not {an attribute}\n";
+
+        let got = render(src, &MarkdownOptions::default());
+
+        assert!(got.contains("<h1 id=\"intro\" class=\"unnumbered big\">Chapter 1</h1>"));
+        assert!(got.contains("<h2>Not {an attribute}</h2>"));
+    }
+
+    #[test]
+    fn links_to_chapters_point_at_their_html() {
+        let src = "[a](./chapter_1.md#intro) [b](../README.md) [c](https://example.com/x.md)";
+
+        let got = render(src, &MarkdownOptions::default());
+
+        assert!(got.contains("href=\"./chapter_1.html#intro\""));
+        assert!(got.contains("href=\"../README.html\""));
+        assert!(got.contains("href=\"https://example.com/x.md\""));
+    }
+
+    #[test]
+    fn ruby_shorthand_is_expanded() {
+        let options = MarkdownOptions {
+            ruby: true,
+            ..Default::default()
+        };
+        let src = "{東京|とうきょう} and {漢字|かん|じ}\n\n```\n{a|b}\n```\n";
+
+        let got = render(src, &options);
+
+        assert!(got.contains(
+            "<ruby>東京<rp>(</rp><rt>とうきょう</rt><rp>)</rp></ruby> and <ruby>\
+             漢<rp>(</rp><rt>かん</rt><rp>)</rp>字<rp>(</rp><rt>じ</rt><rp>)</rp></ruby>"
+        ));
+        assert!(got.contains("<code>{a|b}\n</code>"));
+    }
+
+    #[test]
+    fn web_only_content_is_skipped() {
+        let src =
+            "Both\n\n<!-- epub:skip -->\n<iframe src=\"x\"></iframe>\n<!-- /epub:skip -->\n\n\
+                   <!-- epub:only\n*EPUB* only\n-->\n";
+
+        let got = render(src, &MarkdownOptions::default());
+
+        assert!(got.contains("<p>Both</p>"));
+        assert!(!got.contains("iframe"));
+        assert!(got.contains("<em>EPUB</em> only"));
+    }
+
+    #[test]
+    fn markers_in_code_are_left_alone() {
+        let src = concat!(
+            "```markdown\n<!-- epub:skip -->\nWeb only\n<!-- /epub:skip -->\n```\n\n",
+            "    <!-- epub:only EPUB only -->\n\n",
+            "Write `<!-- epub:only ... -->` for EPUB only content.\n\n",
+            "<!-- epub:skip -->\nGone\n<!-- /epub:skip -->\n",
+        );
+        let should_be = concat!(
+            "```markdown\n<!-- epub:skip -->\nWeb only\n<!-- /epub:skip -->\n```\n\n",
+            "    <!-- epub:only EPUB only -->\n\n",
+            "Write `<!-- epub:only ... -->` for EPUB only content.\n\n",
+            "\n",
+        );
+
+        assert_eq!(conditional_content(src), should_be);
+    }
+
+    #[test]
+    fn curly_quotes_skip_code_blocks() {
+        let options = MarkdownOptions {
+            curly_quotes: Some(true),
+            ..Default::default()
+        };
+        let src = "\"quoted\"\n\n```\n\"code\"\n```\n";
+
+        let got = render(src, &options);
+
+        assert!(got.contains("\u{201c}quoted\u{201d}"));
+        assert!(got.contains("&quot;code&quot;"));
+    }
+}
@@ -0,0 +1,308 @@
+//! Post-processing of the package document (`content.opf`) and navigation
+//! document, for things `epub-builder` doesn't let us set directly.
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use failure::{Error, ResultExt};
+use regex::{Captures, NoExpand, Regex};
+use sha2::{Digest, Sha256};
+use std::io::{Cursor, Read, Write};
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use crate::utils;
+
+/// Where `epub-builder` puts the package document.
+pub(crate) const PACKAGE_DOCUMENT: &str = "OEBPS/content.opf";
+
+/// Where `epub-builder` puts the navigation document.
+pub(crate) const NAVIGATION_DOCUMENT: &str = "OEBPS/nav.xhtml";
+
+/// The format used for `dcterms:modified`, as required by the EPUB 3 spec.
+pub(crate) const MODIFIED_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// Copy a generated book, passing each of the named files through `f` on the
+/// way. Every entry is stamped with the `modified` time, so rebuilding an
+/// unchanged book gives the same file.
+pub(crate) fn rewrite_files<F>(
+    epub: &[u8],
+    names: &[&str],
+    modified: &DateTime<Utc>,
+    mut f: F,
+) -> Result<Vec<u8>, Error>
+where
+    F: FnMut(&str, String) -> String,
+{
+    let mut archive = ZipArchive::new(Cursor::new(epub)).context("Unable to read the book")?;
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let entry_name = entry.name().to_string();
+        // keep the same compression so the `mimetype` file stays uncompressed
+        let options = FileOptions::default()
+            .compression_method(entry.compression())
+            .last_modified_time(zip_time(modified));
+
+        if entry_name.ends_with('/') {
+            writer.add_directory(entry_name, options)?;
+            continue;
+        }
+
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+
+        if names.contains(&entry_name.as_str()) {
+            let text = String::from_utf8(data)
+                .with_context(|_| format!("{} isn't valid UTF-8", entry_name))?;
+            data = f(&entry_name, text).into_bytes();
+        }
+
+        writer.start_file(entry_name, options)?;
+        writer.write_all(&data)?;
+    }
+
+    Ok(writer.finish()?.into_inner())
+}
+
+/// Zip files can only store times between 1980 and 2107, so anything else is
+/// clamped to the start of 1980.
+fn zip_time(timestamp: &DateTime<Utc>) -> zip::DateTime {
+    zip::DateTime::from_date_and_time(
+        timestamp.year() as u16,
+        timestamp.month() as u8,
+        timestamp.day() as u8,
+        timestamp.hour() as u8,
+        timestamp.minute() as u8,
+        timestamp.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+/// A `urn:uuid:` identifier derived from the book's metadata, so it stays the
+/// same between builds (`epub-builder` picks a random one every time).
+pub(crate) fn identifier(metadata: &[&str]) -> String {
+    let hash = Sha256::digest(metadata.join("\0").as_bytes());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    // mark it as a custom (version 8) RFC 4122 UUID
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "urn:uuid:{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Replace the book's unique identifier.
+pub(crate) fn set_identifier(opf: &str, identifier: &str) -> String {
+    lazy_static! {
+        static ref IDENTIFIER: Regex =
+            Regex::new(r#"(<dc:identifier id="epub-id-1">)[^<]*(</dc:identifier>)"#).unwrap();
+    }
+
+    IDENTIFIER
+        .replace(opf, |caps: &Captures<'_>| {
+            format!("{}{}{}", &caps[1], identifier, &caps[2])
+        })
+        .into_owned()
+}
+
+/// Set the publication date (`dc:date`) and the `dcterms:modified`
+/// timestamp, replacing whatever was there before.
+pub(crate) fn set_dates(opf: &str, date: Option<&str>, modified: &str) -> String {
+    lazy_static! {
+        static ref DATES: Regex = Regex::new(
+            r#"\s*(<dc:date[^>]*>[^<]*</dc:date>|<meta\s+property="dcterms:modified"[^>]*>[^<]*</meta>)"#
+        )
+        .unwrap();
+        static ref END_OF_METADATA: Regex = Regex::new(r"\s*</metadata>").unwrap();
+    }
+
+    let mut metadata = String::new();
+    if let Some(date) = date {
+        metadata.push_str(&format!("\n    <dc:date>{}</dc:date>", date));
+    }
+    metadata.push_str(&format!(
+        "\n    <meta property=\"dcterms:modified\">{}</meta>\n  </metadata>",
+        modified
+    ));
+
+    let opf = DATES.replace_all(opf, "");
+    END_OF_METADATA
+        .replace(&opf, NoExpand(&metadata))
+        .into_owned()
+}
+
+/// A generated page which should be listed in the guide and landmarks.
+/// `epub-builder` only gives pages a title by also putting them in the table
+/// of contents, so these are added afterwards.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Landmark {
+    /// The page, relative to the `OEBPS/` root.
+    pub(crate) href: String,
+    /// What the page is, as an `epub:type` (e.g. `titlepage`).
+    pub(crate) epub_type: &'static str,
+    pub(crate) title: String,
+}
+
+/// Fill in the titles `epub-builder` left empty in the guide.
+pub(crate) fn set_guide_titles(opf: &str, landmarks: &[Landmark]) -> String {
+    lazy_static! {
+        static ref UNTITLED: Regex =
+            Regex::new(r#"(<reference type="[^"]*" title=")(" href="([^"]*)")"#).unwrap();
+    }
+
+    UNTITLED
+        .replace_all(opf, |caps: &Captures<'_>| {
+            match landmarks.iter().find(|landmark| landmark.href == caps[3]) {
+                Some(landmark) => {
+                    format!(
+                        "{}{}{}",
+                        &caps[1],
+                        utils::escape_html(&landmark.title),
+                        &caps[2]
+                    )
+                }
+                None => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// Add the `landmarks` to the navigation document, after any `epub-builder`
+/// listed itself.
+pub(crate) fn add_landmarks(nav: &str, landmarks: &[Landmark]) -> String {
+    lazy_static! {
+        static ref LANDMARKS: Regex =
+            Regex::new(r#"(?s)(<nav epub:type\s*=\s*"landmarks">)(.*?)</nav>"#).unwrap();
+    }
+
+    if landmarks.is_empty() {
+        return nav.to_string();
+    }
+
+    let items: String = landmarks
+        .iter()
+        .map(|landmark| {
+            format!(
+                "<li><a epub:type=\"{}\" href=\"{}\">{}</a></li>\n",
+                landmark.epub_type,
+                landmark.href,
+                utils::escape_html(&landmark.title)
+            )
+        })
+        .collect();
+
+    LANDMARKS
+        .replace(nav, |caps: &Captures<'_>| {
+            let list = caps[2].trim_end();
+            let list = if list.ends_with("</ol>") {
+                let existing = list[..list.len() - "</ol>".len()].trim_end();
+                format!("{}\n{}</ol>", existing, items)
+            } else {
+                format!("\n<ol>\n{}</ol>", items)
+            };
+            format!("{}{}\n</nav>", &caps[1], list)
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn replace_the_modified_timestamp() {
+        let src = "<metadata>\n    <dc:title>Book</dc:title>\n    \
+                   <meta property=\"dcterms:modified\">2020-01-01T00:00:00Z</meta>\n  \
+                   </metadata>";
+        let should_be = "<metadata>\n    <dc:title>Book</dc:title>\n    \
+                         <dc:date>2019-05-01</dc:date>\n    \
+                         <meta property=\"dcterms:modified\">2019-05-02T10:00:00Z</meta>\n  \
+                         </metadata>";
+
+        let got = set_dates(src, Some("2019-05-01"), "2019-05-02T10:00:00Z");
+
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn identifiers_only_depend_on_the_metadata() {
+        let first = identifier(&["My Book", "Jane Doe", "en"]);
+        let second = identifier(&["My Book", "Jane Doe", "en"]);
+        let other = identifier(&["My Book", "Jane Doe", "de"]);
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert!(first.starts_with("urn:uuid:"));
+        assert_eq!(first.len(), "urn:uuid:".len() + 36);
+        assert_eq!(&first[23..24], "8");
+    }
+
+    #[test]
+    fn replace_the_identifier() {
+        let src = "<dc:identifier id=\"epub-id-1\">urn:uuid:1234</dc:identifier>";
+        let should_be = "<dc:identifier id=\"epub-id-1\">urn:uuid:5678</dc:identifier>";
+
+        assert_eq!(set_identifier(src, "urn:uuid:5678"), should_be);
+    }
+
+    fn landmarks() -> Vec<Landmark> {
+        vec![Landmark {
+            href: String::from("titlepage.xhtml"),
+            epub_type: "titlepage",
+            title: String::from("Title & Author"),
+        }]
+    }
+
+    #[test]
+    fn give_guide_references_a_title() {
+        let src = concat!(
+            "<reference type=\"toc\" title=\"Contents\" href=\"nav.xhtml\" />\n",
+            "<reference type=\"title-page\" title=\"\" href=\"titlepage.xhtml\" />\n",
+        );
+        let should_be = concat!(
+            "<reference type=\"toc\" title=\"Contents\" href=\"nav.xhtml\" />\n",
+            "<reference type=\"title-page\" title=\"Title &amp; Author\" ",
+            "href=\"titlepage.xhtml\" />\n",
+        );
+
+        assert_eq!(set_guide_titles(src, &landmarks()), should_be);
+    }
+
+    #[test]
+    fn add_the_missing_landmarks() {
+        let src = "<nav epub:type = \"landmarks\">\n    \n  </nav>";
+        let should_be = "<nav epub:type = \"landmarks\">\n<ol>\n\
+                         <li><a epub:type=\"titlepage\" href=\"titlepage.xhtml\">\
+                         Title &amp; Author</a></li>\n</ol>\n</nav>";
+        assert_eq!(add_landmarks(src, &landmarks()), should_be);
+
+        let src = "<nav epub:type = \"landmarks\">\n    <ol>\n\
+                   <li><a epub:type=\"toc\" href=\"toc.xhtml\">Contents</a></li>\n\n\
+                   </ol>\n  </nav>";
+        let should_be = "<nav epub:type = \"landmarks\">\n    <ol>\n\
+                         <li><a epub:type=\"toc\" href=\"toc.xhtml\">Contents</a></li>\n\
+                         <li><a epub:type=\"titlepage\" href=\"titlepage.xhtml\">\
+                         Title &amp; Author</a></li>\n</ol>\n</nav>";
+        assert_eq!(add_landmarks(src, &landmarks()), should_be);
+    }
+
+    #[test]
+    fn zip_times_are_clamped() {
+        let timestamp = Utc.ymd(2019, 5, 2).and_hms(10, 30, 0);
+        let got = zip_time(&timestamp);
+        assert_eq!((got.year(), got.month(), got.day()), (2019, 5, 2));
+        assert_eq!((got.hour(), got.minute(), got.second()), (10, 30, 0));
+
+        let got = zip_time(&Utc.ymd(1970, 1, 1).and_hms(0, 0, 0));
+        assert_eq!((got.year(), got.month(), got.day()), (1980, 1, 1));
+    }
+}
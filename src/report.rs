@@ -0,0 +1,252 @@
+//! A summary of what would go into the book, without actually generating it.
+
+use failure::{Error, ResultExt};
+use mdbook::book::BookItem;
+use mdbook::renderer::RenderContext;
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::links::{self, BrokenLink};
+use crate::markdown;
+use crate::resources;
+
+/// Everything we found out about a book while doing a dry run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub chapters: Vec<ChapterReport>,
+    pub assets: Vec<AssetReport>,
+    pub broken_links: Vec<BrokenLink>,
+    /// A rough estimate of the book's size in bytes, before compression.
+    pub estimated_size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChapterReport {
+    pub title: String,
+    /// The chapter's path, relative to the `src/` directory.
+    pub path: PathBuf,
+    /// How deeply nested the chapter is in the table of contents.
+    pub level: usize,
+    /// The size of the chapter's rendered HTML, in bytes.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AssetReport {
+    /// The asset's path, relative to the `src/` directory.
+    pub path: PathBuf,
+    pub mimetype: String,
+    pub size: u64,
+}
+
+impl Report {
+    /// Inspect the book, figuring out which chapters and assets would be
+    /// included and checking its links.
+    pub fn from_render_context(ctx: &RenderContext) -> Result<Report, Error> {
+        let config = Config::from_render_context(ctx)?;
+        let mut chapters = Vec::new();
+
+        for item in ctx.book.iter() {
+            if let BookItem::Chapter(ref ch) = *item {
+                let html = markdown::render(&ch.content, &config.markdown);
+
+                chapters.push(ChapterReport {
+                    title: format!("{}", ch),
+                    path: ch.path.clone(),
+                    level: ch.number.as_ref().map(|n| n.len() - 1).unwrap_or(0),
+                    size: html.len() as u64,
+                });
+            }
+        }
+
+        let mut assets = Vec::new();
+        for asset in resources::find(ctx)? {
+            let size = fs::metadata(&asset.location_on_disk)
+                .with_context(|_| format!("Unable to stat {}", asset.location_on_disk.display()))?
+                .len();
+
+            assets.push(AssetReport {
+                path: asset.filename,
+                mimetype: asset.mimetype.to_string(),
+                size,
+            });
+        }
+
+        let broken_links = links::broken_links(ctx)?;
+
+        let estimated_size = chapters.iter().map(|c| c.size).sum::<u64>()
+            + assets.iter().map(|a| a.size).sum::<u64>();
+
+        Ok(Report {
+            chapters,
+            assets,
+            broken_links,
+            estimated_size,
+        })
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Chapters ({}):", self.chapters.len())?;
+        for ch in &self.chapters {
+            let indent = "  ".repeat(ch.level + 1);
+            writeln!(
+                f,
+                "{}{} ({}, {} bytes)",
+                indent,
+                ch.title,
+                ch.path.display(),
+                ch.size
+            )?;
+        }
+
+        writeln!(f, "Assets ({}):", self.assets.len())?;
+        for asset in &self.assets {
+            writeln!(
+                f,
+                "  {} ({}, {} bytes)",
+                asset.path.display(),
+                asset.mimetype,
+                asset.size
+            )?;
+        }
+
+        if !self.broken_links.is_empty() {
+            writeln!(f, "Broken links ({}):", self.broken_links.len())?;
+            for link in &self.broken_links {
+                writeln!(f, "  {} in {}", link.link, link.chapter.display())?;
+            }
+        }
+
+        writeln!(
+            f,
+            "Estimated size: {} bytes (uncompressed)",
+            self.estimated_size
+        )
+    }
+}
+
+/// The book's reading order and table of contents, as they were actually
+/// generated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpineReport {
+    /// Every file in the spine, in reading order.
+    pub spine: Vec<String>,
+    pub toc: Vec<TocEntry>,
+    /// Anything which looks wrong, e.g. TOC entries pointing at files which
+    /// aren't in the spine.
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TocEntry {
+    pub title: String,
+    pub href: String,
+    pub level: i32,
+}
+
+impl SpineReport {
+    pub(crate) fn new(spine: Vec<String>, toc: Vec<TocEntry>) -> SpineReport {
+        let mut warnings = Vec::new();
+
+        let mut seen = HashSet::new();
+        for file in &spine {
+            if !seen.insert(file) {
+                warnings.push(format!("{} is in the spine more than once", file));
+            }
+        }
+
+        let mut hrefs = HashSet::new();
+        let mut previous_level = None;
+        for entry in &toc {
+            let file = entry.href.split('#').next().unwrap_or_default();
+            if !spine.iter().any(|f| f == file) {
+                warnings.push(format!(
+                    "\"{}\" points at {}, which isn't in the spine",
+                    entry.title, file
+                ));
+            }
+
+            if !hrefs.insert(&entry.href) {
+                warnings.push(format!(
+                    "\"{}\" has the same href as another entry ({})",
+                    entry.title, entry.href
+                ));
+            }
+
+            if let Some(previous) = previous_level {
+                if entry.level > previous + 1 {
+                    warnings.push(format!(
+                        "\"{}\" is nested more than one level below the entry before it",
+                        entry.title
+                    ));
+                }
+            }
+            previous_level = Some(entry.level);
+        }
+
+        SpineReport {
+            spine,
+            toc,
+            warnings,
+        }
+    }
+}
+
+impl Display for SpineReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Spine ({}):", self.spine.len())?;
+        for (i, file) in self.spine.iter().enumerate() {
+            writeln!(f, "  {}. {}", i + 1, file)?;
+        }
+
+        let min_level = self.toc.iter().map(|e| e.level).min().unwrap_or(0);
+        writeln!(f, "Table of contents ({}):", self.toc.len())?;
+        for entry in &self.toc {
+            let indent = "  ".repeat((entry.level - min_level) as usize + 1);
+            writeln!(
+                f,
+                "{}{} ({}, level {})",
+                indent, entry.title, entry.href, entry.level
+            )?;
+        }
+
+        if !self.warnings.is_empty() {
+            writeln!(f, "Warnings ({}):", self.warnings.len())?;
+            for warning in &self.warnings {
+                writeln!(f, "  {}", warning)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_toc_entries_outside_the_spine() {
+        let entry = |title: &str, href: &str, level| TocEntry {
+            title: title.to_string(),
+            href: href.to_string(),
+            level,
+        };
+        let spine = vec![String::from("cover.xhtml"), String::from("intro.html")];
+        let toc = vec![
+            entry("Intro", "intro.html", 0),
+            entry("Usage", "usage.html", 2),
+            entry("Intro Again", "intro.html", 0),
+        ];
+
+        let got = SpineReport::new(spine, toc);
+
+        assert_eq!(got.warnings.len(), 3);
+        assert!(got.warnings[0].contains("usage.html"));
+    }
+}
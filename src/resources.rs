@@ -4,10 +4,29 @@ use mdbook::renderer::RenderContext;
 use mime_guess::{self, Mime};
 use pulldown_cmark::{Event, Parser, Tag};
 use regex::Regex;
-use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::links;
+use crate::markdown;
 
 pub(crate) fn find(ctx: &RenderContext) -> Result<Vec<Asset>, Error> {
-    let mut assets = Vec::new();
+    let (assets, mut errors) = find_all(ctx)?;
+
+    if errors.is_empty() {
+        Ok(assets)
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+/// Find every asset, carrying on past links which can't be resolved so they
+/// can all be reported at once.
+pub(crate) fn find_all(ctx: &RenderContext) -> Result<(Vec<Asset>, Vec<Error>), Error> {
+    let mut assets: Vec<Asset> = Vec::new();
+    let mut errors = Vec::new();
     let src_dir = ctx
         .root
         .join(&ctx.config.book.src)
@@ -23,16 +42,158 @@ pub(crate) fn find(ctx: &RenderContext) -> Result<Vec<Asset>, Error> {
                 full_path.push(s);
             }
             full_path.pop();
-            let found = assets_in_markdown(&ch.content, &full_path)?;
+            let content = markdown::conditional_content(&ch.content);
 
-            for full_filename in found {
-                let relative = full_filename.strip_prefix(&src_dir).unwrap();
-                assets.push(Asset::new(relative, &full_filename));
+            for link in links_in_markdown(&content) {
+                let full_filename = match resolve_asset(&full_path, &link) {
+                    Ok(filename) => filename,
+                    Err(e) => {
+                        errors.push(e);
+                        continue;
+                    }
+                };
+                let reference = (ch.path.clone(), link);
+
+                // the same asset may be used by several chapters, but it
+                // only needs to be embedded once
+                match assets
+                    .iter_mut()
+                    .find(|a| a.location_on_disk == full_filename)
+                {
+                    Some(existing) => existing.references.push(reference),
+                    None => {
+                        let relative = match relative_asset_path(&ch.path, &reference.1) {
+                            Ok(relative) => relative,
+                            Err(e) => {
+                                errors.push(e);
+                                continue;
+                            }
+                        };
+                        let mut asset = Asset::new(relative, &full_filename);
+
+                        if let Some(real_name) = real_file_name(&full_path.join(&reference.1)) {
+                            log::warn!(
+                                "The link to \"{}\" in {} doesn't match the case of the file \
+                                 on disk ({}), which breaks in most readers",
+                                reference.1,
+                                ch.path.display(),
+                                real_name
+                            );
+                            asset.filename.set_file_name(real_name);
+                        }
+
+                        asset.references.push(reference);
+                        assets.push(asset);
+                    }
+                }
             }
         }
     }
 
-    Ok(assets)
+    disambiguate(&mut assets)?;
+
+    Ok((assets, errors))
+}
+
+/// An asset's path relative to `src/`, worked out from the link rather than
+/// where the file really is. That way symlinks (which may point outside
+/// `src/`) are embedded where the chapter expects them.
+fn relative_asset_path(chapter: &Path, link: &str) -> Result<PathBuf, Error> {
+    let parent = chapter.parent().unwrap_or_else(|| Path::new(""));
+    let mut relative = PathBuf::new();
+
+    for component in parent.join(link).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !relative.pop() {
+                    return Err(failure::err_msg(format!(
+                        "\"{}\" in {} points outside the src directory",
+                        link,
+                        chapter.display()
+                    )));
+                }
+            }
+            Component::Normal(name) => relative.push(name),
+            _ => {
+                return Err(failure::err_msg(format!(
+                    "\"{}\" in {} isn't a relative link",
+                    link,
+                    chapter.display()
+                )))
+            }
+        }
+    }
+
+    Ok(relative)
+}
+
+/// On case-insensitive file systems a link can find a file even though the
+/// case doesn't match, but that breaks in readers. Returns the file's real
+/// name if they don't match.
+fn real_file_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let names: Vec<String> = fs::read_dir(path.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+
+    if names.iter().any(|n| n == name) {
+        return None;
+    }
+
+    names
+        .into_iter()
+        .find(|n| n.to_lowercase() == name.to_lowercase())
+}
+
+/// Paths which only differ by case (or path separator) can't be told apart
+/// by many readers, so give any colliding assets a unique name by appending
+/// a hash of their original name and contents. The name is included so
+/// identical copies of a file still end up with different names.
+fn disambiguate(assets: &mut [Asset]) -> Result<(), Error> {
+    let mut names: HashMap<String, usize> = HashMap::new();
+    for asset in assets.iter() {
+        *names.entry(normalized_name(&asset.filename)).or_insert(0) += 1;
+    }
+
+    for asset in assets.iter_mut() {
+        if names[&normalized_name(&asset.filename)] < 2 {
+            continue;
+        }
+
+        let data = fs::read(&asset.location_on_disk)
+            .with_context(|_| format!("Unable to read {}", asset.location_on_disk.display()))?;
+        let mut hasher = Sha256::new();
+        hasher.input(asset.filename.to_string_lossy().as_bytes());
+        hasher.input(&data);
+        let hash = format!("{:x}", hasher.result());
+
+        let stem = asset
+            .filename
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let renamed = match asset.filename.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, &hash[..8], ext.to_string_lossy()),
+            None => format!("{}-{}", stem, &hash[..8]),
+        };
+        let renamed = asset.filename.with_file_name(renamed);
+
+        log::debug!(
+            "Renaming {} to {} to avoid a collision",
+            asset.filename.display(),
+            renamed.display()
+        );
+        asset.filename = renamed;
+    }
+
+    Ok(())
+}
+
+fn normalized_name(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/").to_lowercase()
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -42,6 +203,8 @@ pub(crate) struct Asset {
     /// The asset's filename relative to the `src/` directory.
     pub(crate) filename: PathBuf,
     pub(crate) mimetype: Mime,
+    /// Every chapter which refers to this asset, and the link it used.
+    pub(crate) references: Vec<(PathBuf, String)>,
 }
 
 impl Asset {
@@ -57,11 +220,13 @@ impl Asset {
             location_on_disk,
             filename: filename.into(),
             mimetype: mt,
+            references: Vec::new(),
         }
     }
 }
 
-fn assets_in_markdown(src: &str, parent_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+/// Find the links to every image (or other embedded media) in a chapter.
+fn links_in_markdown(src: &str) -> Vec<String> {
     let mut found = Vec::new();
     for event in Parser::new(src) {
         match event {
@@ -70,16 +235,19 @@ fn assets_in_markdown(src: &str, parent_dir: &Path) -> Result<Vec<PathBuf>, Erro
             }
             Event::Html(html) => {
                 lazy_static! {
-                    static ref HTML_LINK: Regex =
-                        Regex::new(r#"(<(?:a|img) [^>]*?(?:src|href)=")([^"]+?)""#).unwrap();
-                }
-                let captures = HTML_LINK.captures(&html);
-                if !captures.is_none() {
-                    let path = captures.unwrap().get(2);
-                    if !path.is_none() {
-                        found.push(path.unwrap().as_str().to_string());
-                    }
+                    // media can also be pulled in with <object data="...">,
+                    // <embed src="..."> and <source src="...">
+                    static ref HTML_LINK: Regex = Regex::new(
+                        r#"(<(?:a|img|object|embed|source)\s(?:[^>]*?\s)?(?:src|href|data)=")([^"]+?)""#
+                    )
+                    .unwrap();
                 }
+                let links = HTML_LINK
+                    .captures_iter(&html)
+                    .filter_map(|captures| captures.get(2))
+                    .map(|link| link.as_str())
+                    .filter(|link| links::is_relative(link) && !is_chapter_link(link));
+                found.extend(links.map(String::from));
             }
             _ => {
             }
@@ -94,37 +262,50 @@ fn assets_in_markdown(src: &str, parent_dir: &Path) -> Result<Vec<PathBuf>, Erro
     // be nice if we could have some sort of caching mechanism by using the
     // destination directory (hash the URL and store it as
     // `book/epub/cache/$hash.$ext`?).
-    let mut assets = Vec::new();
+    found
+}
 
-    for link in found {
-        let mut filename = parent_dir.to_path_buf();
-        for s in link.split("/") {
-            filename.push(s);
-        }
-        let filename = filename.canonicalize().with_context(|_| {
-            format!(
-                "Unable to fetch the canonical path for {}",
-                filename.display()
-            )
-        })?;
-
-        if !filename.is_file() {
-            return Err(failure::err_msg(format!(
-                "Asset was not a file, {}",
-                filename.display()
-            )));
-        }
+/// Does this link point at another chapter rather than an asset?
+fn is_chapter_link(link: &str) -> bool {
+    let path = link.split('#').next().unwrap_or(link);
+    path.ends_with(".md") || path.ends_with(".html")
+}
+
+/// Find an asset on disk, relative to the directory containing the chapter
+/// which links to it.
+fn resolve_asset(parent_dir: &Path, link: &str) -> Result<PathBuf, Error> {
+    let mut filename = parent_dir.to_path_buf();
+    for s in link.split("/") {
+        filename.push(s);
+    }
+    let filename = filename.canonicalize().with_context(|_| {
+        format!(
+            "Unable to fetch the canonical path for {}",
+            filename.display()
+        )
+    })?;
 
-        assets.push(filename);
+    if !filename.is_file() {
+        return Err(failure::err_msg(format!(
+            "Asset was not a file, {}",
+            filename.display()
+        )));
     }
 
-    Ok(assets)
+    Ok(filename)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn assets_in_markdown(src: &str, parent_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        links_in_markdown(src)
+            .iter()
+            .map(|link| resolve_asset(parent_dir, link))
+            .collect()
+    }
+
     #[test]
     fn find_images() {
         let parent_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dummy/src");
@@ -139,4 +320,59 @@ mod tests {
 
         assert_eq!(got, should_be);
     }
+
+    #[test]
+    fn links_are_resolved_relative_to_the_chapter() {
+        let chapter = Path::new("guide/usage.md");
+
+        assert_eq!(
+            relative_asset_path(chapter, "../images/./logo.png").unwrap(),
+            PathBuf::from("images/logo.png")
+        );
+        assert!(relative_asset_path(chapter, "../../logo.png").is_err());
+    }
+
+    #[test]
+    fn find_the_real_case_of_a_file() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dummy/src");
+
+        assert_eq!(
+            real_file_name(&dir.join("Rust-Logo.PNG")),
+            Some(String::from("rust-logo.png"))
+        );
+        assert_eq!(real_file_name(&dir.join("rust-logo.png")), None);
+    }
+
+    #[test]
+    fn find_embedded_media() {
+        let parent_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dummy/src");
+        let src = "<object type=\"image/svg+xml\" data=\"reddit.svg\"></object>\n\n\
+                   <video><source src=\"rust-logo.png\" /></video>\n\n\
+                   <embed src=\"reddit.svg\" />\n";
+        let should_be = vec![
+            parent_dir.join("reddit.svg").canonicalize().unwrap(),
+            parent_dir.join("rust-logo.png").canonicalize().unwrap(),
+            parent_dir.join("reddit.svg").canonicalize().unwrap(),
+        ];
+
+        let got = assets_in_markdown(src, &parent_dir).unwrap();
+
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn ignore_links_which_arent_assets() {
+        let parent_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dummy/src");
+        let src = concat!(
+            "<p><a href=\"https://www.rust-lang.org/\">Rust</a> and ",
+            "<a href=\"chapter_1.md#intro\">chapter 1</a></p>\n\n",
+            "<video><source src=\"https://example.com/clip.mp4\" />",
+            "<source src=\"rust-logo.png\" /></video>\n",
+        );
+        let should_be = vec![parent_dir.join("rust-logo.png").canonicalize().unwrap()];
+
+        let got = assets_in_markdown(src, &parent_dir).unwrap();
+
+        assert_eq!(got, should_be);
+    }
 }
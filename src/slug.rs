@@ -0,0 +1,99 @@
+//! Turning chapter paths into plain ASCII file names, for readers which
+//! struggle with anything else.
+
+use std::collections::BTreeMap;
+
+/// Transliterations for the accented Latin letters we're most likely to see.
+/// Anything not in here (or the user's own rules) is written out as its code
+/// point.
+#[rustfmt::skip]
+const TRANSLITERATIONS: &[(char, &str)] = &[
+    ('à', "a"), ('á', "a"), ('â', "a"), ('ã', "a"), ('ä', "a"), ('å', "a"),
+    ('ā', "a"), ('ă', "a"), ('ą', "a"), ('æ', "ae"), ('ç', "c"), ('ć', "c"),
+    ('č', "c"), ('ď', "d"), ('đ', "d"), ('ð', "d"), ('è', "e"), ('é', "e"),
+    ('ê', "e"), ('ë', "e"), ('ē', "e"), ('ė', "e"), ('ę', "e"), ('ě', "e"),
+    ('ğ', "g"), ('ì', "i"), ('í', "i"), ('î', "i"), ('ï', "i"), ('ī', "i"),
+    ('ı', "i"), ('ł', "l"), ('ñ', "n"), ('ń', "n"), ('ň', "n"), ('ò', "o"),
+    ('ó', "o"), ('ô', "o"), ('õ', "o"), ('ö', "o"), ('ø', "o"), ('ō', "o"),
+    ('ő', "o"), ('œ', "oe"), ('ř', "r"), ('ś', "s"), ('š', "s"), ('ş', "s"),
+    ('ß', "ss"), ('ť', "t"), ('ţ', "t"), ('þ', "th"), ('ù', "u"), ('ú', "u"),
+    ('û', "u"), ('ü', "u"), ('ū', "u"), ('ů', "u"), ('ű', "u"), ('ý', "y"),
+    ('ÿ', "y"), ('ź', "z"), ('ż', "z"), ('ž', "z"),
+];
+
+/// Slugify each component of a `/` separated path, leaving the extension
+/// alone. The user's `rules` are applied before the built-in ones.
+pub(crate) fn slugify_path(path: &str, rules: &BTreeMap<String, String>) -> String {
+    let (stem, extension) = match path.rfind('.') {
+        Some(i) if !path[i..].contains('/') => (&path[..i], &path[i..]),
+        _ => (path, ""),
+    };
+
+    let components: Vec<String> = stem.split('/').map(|c| slugify(c, rules)).collect();
+
+    format!("{}{}", components.join("/"), extension)
+}
+
+/// Add a numeric suffix to a path, before its extension, to tell it apart
+/// from another path with the same slug.
+pub(crate) fn with_suffix(path: &str, n: usize) -> String {
+    match path.rfind('.') {
+        Some(i) if !path[i..].contains('/') => format!("{}-{}{}", &path[..i], n, &path[i..]),
+        _ => format!("{}-{}", path, n),
+    }
+}
+
+fn slugify(name: &str, rules: &BTreeMap<String, String>) -> String {
+    let mut name = name.to_lowercase();
+    for (from, to) in rules {
+        name = name.replace(&from.to_lowercase(), to);
+    }
+
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            slug.push(c);
+        } else if let Some(&(_, replacement)) = TRANSLITERATIONS.iter().find(|t| t.0 == c) {
+            slug.push_str(replacement);
+        } else if c.is_alphanumeric() {
+            slug.push_str(&format!("u{:x}", c as u32));
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let slug = slug.trim_matches('-');
+    if slug.is_empty() {
+        String::from("-")
+    } else {
+        slug.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slugify_chapter_paths() {
+        let mut rules = BTreeMap::new();
+        rules.insert(String::from("ü"), String::from("ue"));
+
+        let inputs = vec![
+            ("chapter_1.html", "chapter_1.html"),
+            ("Über uns/Straße.html", "ueber-uns/strasse.html"),
+            ("Ça va? Très bien!.html", "ca-va-tres-bien.html"),
+            ("中文.html", "u4e2du6587.html"),
+        ];
+
+        for (src, should_be) in inputs {
+            assert_eq!(slugify_path(src, &rules), should_be);
+        }
+    }
+
+    #[test]
+    fn suffixes_go_before_the_extension() {
+        assert_eq!(with_suffix("guide/uber.html", 2), "guide/uber-2.html");
+        assert_eq!(with_suffix("v1.0/readme", 3), "v1.0/readme-3");
+    }
+}
@@ -0,0 +1,115 @@
+//! Timing and memory usage for each phase of generating a book, to help
+//! figure out where the time goes with very large books.
+
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::time::{Duration, Instant};
+
+/// How long each phase of generating a book took.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    pub phases: Vec<Phase>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Phase {
+    pub name: String,
+    pub duration: Duration,
+    /// The process's peak memory usage (in bytes) by the end of the phase,
+    /// where the platform lets us find out.
+    pub peak_memory: Option<u64>,
+}
+
+impl Stats {
+    /// Record a phase which began at `start` and just finished.
+    pub(crate) fn record(&mut self, name: &str, start: Instant) {
+        let duration = start.elapsed();
+        log::debug!("{} took {:?}", name, duration);
+
+        self.phases.push(Phase {
+            name: name.to_string(),
+            duration,
+            peak_memory: peak_memory(),
+        });
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|p| p.duration).sum()
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for phase in &self.phases {
+            write!(
+                f,
+                "  {:<20} {:>10.3}s",
+                phase.name,
+                phase.duration.as_secs_f64()
+            )?;
+            match phase.peak_memory {
+                Some(bytes) => writeln!(f, "  (peak memory {:.1} MiB)", mebibytes(bytes))?,
+                None => writeln!(f)?,
+            }
+        }
+
+        writeln!(f, "  {:<20} {:>10.3}s", "total", self.total().as_secs_f64())
+    }
+}
+
+fn mebibytes(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+/// Read the peak resident set size from `/proc`, which only exists on Linux.
+fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_are_recorded_in_order() {
+        let mut stats = Stats::default();
+
+        stats.record("prepare", Instant::now());
+        stats.record("write zip", Instant::now());
+
+        let names: Vec<&str> = stats.phases.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["prepare", "write zip"]);
+        assert_eq!(
+            stats.total(),
+            stats.phases[0].duration + stats.phases[1].duration
+        );
+    }
+
+    #[test]
+    fn print_each_phase_and_the_total() {
+        let phase = |name: &str, millis, peak_memory| Phase {
+            name: name.to_string(),
+            duration: Duration::from_millis(millis),
+            peak_memory,
+        };
+        let stats = Stats {
+            phases: vec![
+                phase("prepare", 250, Some(3 * 1024 * 1024)),
+                phase("write zip", 1500, None),
+            ],
+        };
+        let should_be = concat!(
+            "  prepare                   0.250s  (peak memory 3.0 MiB)\n",
+            "  write zip                 1.500s\n",
+            "  total                     1.750s\n",
+        );
+
+        let got = stats.to_string();
+
+        assert_eq!(got, should_be);
+    }
+}
@@ -1,4 +1,5 @@
 use failure::SyncFailure;
+use pulldown_cmark::{Event, Parser, Tag};
 use std::error::Error as StdError;
 
 pub(crate) trait ResultExt<T, E> {
@@ -17,3 +18,108 @@ impl<T, E> ResultExt<T, E> for Result<T, E> {
         self.map_err(SyncFailure::new)
     }
 }
+
+/// Escape text so it can be used in XHTML content and attribute values.
+pub(crate) fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Decode any `%XX` escapes in a link, like the ones `pulldown-cmark` adds
+/// for non-ASCII characters.
+pub(crate) fn percent_decode(link: &str) -> String {
+    let bytes = link.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).and_then(|hex| {
+            let hex = std::str::from_utf8(hex).ok()?;
+            u8::from_str_radix(hex, 16).ok()
+        });
+
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Get the plain text of the first paragraph in some markdown, truncated to
+/// roughly `max_len` characters at a word boundary.
+pub(crate) fn first_paragraph(markdown: &str, max_len: usize) -> Option<String> {
+    let mut text = String::new();
+    let mut in_paragraph = false;
+
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::Paragraph) => in_paragraph = true,
+            Event::End(Tag::Paragraph) if !text.trim().is_empty() => break,
+            Event::End(Tag::Paragraph) => in_paragraph = false,
+            Event::Text(t) | Event::Code(t) if in_paragraph => text.push_str(&t),
+            Event::SoftBreak | Event::HardBreak if in_paragraph => text.push(' '),
+            _ => {}
+        }
+    }
+
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        return None;
+    }
+
+    if text.chars().count() <= max_len {
+        return Some(text);
+    }
+
+    let mut truncated = String::new();
+    for word in text.split(' ') {
+        if truncated.chars().count() + word.chars().count() + 1 > max_len {
+            break;
+        }
+        if !truncated.is_empty() {
+            truncated.push(' ');
+        }
+        truncated.push_str(word);
+    }
+    truncated.push('\u{2026}');
+
+    Some(truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_is_stripped_from_the_first_paragraph() {
+        let src = "# Chapter 1\n\nSome *emphasised* text\nwith `code` and [a link](x.md).\n\nMore.";
+
+        let got = first_paragraph(src, 100).unwrap();
+
+        assert_eq!(got, "Some emphasised text with code and a link.");
+    }
+
+    #[test]
+    fn long_paragraphs_are_truncated_at_a_word() {
+        let got = first_paragraph("one two three four", 10).unwrap();
+
+        assert_eq!(got, "one two\u{2026}");
+    }
+
+    #[test]
+    fn decode_escaped_links() {
+        assert_eq!(percent_decode("%C3%9Cber%20uns.html#a"), "Über uns.html#a");
+        assert_eq!(percent_decode("100%.html"), "100%.html");
+    }
+}
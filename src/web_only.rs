@@ -0,0 +1,144 @@
+//! Stripping the constructs which only make sense on the website (links to
+//! `print.html`, the 404 page, unexpanded preprocessor directives) when they
+//! leak into a book's content.
+
+use mdbook::book::Chapter;
+use regex::{Captures, Regex};
+use std::path::Path;
+
+use crate::config::WebOnly;
+
+/// Pages the HTML renderer generates for itself.
+const WEB_PAGES: &[&str] = &["404.md", "print.md"];
+
+/// Directives which embed a runnable playground.
+const PLAYGROUND_DIRECTIVES: &[&str] = &["playground", "playpen"];
+
+/// Is this one of the HTML renderer's own pages?
+pub(crate) fn is_web_page(ch: &Chapter) -> bool {
+    WEB_PAGES.iter().any(|page| ch.path == Path::new(page))
+}
+
+/// Strip web-only constructs from a rendered chapter.
+pub(crate) fn strip(html: &str, options: &WebOnly) -> String {
+    let mut html = html.to_string();
+
+    if options.playground || options.directives {
+        html = strip_directives(&html, options);
+    }
+    if options.print_links {
+        html = strip_print_links(&html);
+    }
+
+    html
+}
+
+/// Remove any `{{#...}}` directives a preprocessor should have expanded,
+/// along with the paragraphs they leave empty. Directives inside `<code>` or
+/// `<pre>` are examples, so they're left alone.
+fn strip_directives(html: &str, options: &WebOnly) -> String {
+    lazy_static! {
+        static ref CODE: Regex = Regex::new(r"(?s)<pre\b.*?</pre>|<code\b.*?</code>").unwrap();
+        static ref DIRECTIVE: Regex = Regex::new(r"\{\{#\s*(\w+)[^}]*\}\}").unwrap();
+        static ref EMPTY_PARAGRAPH: Regex = Regex::new(r"<p>\s*</p>\n?").unwrap();
+    }
+
+    let strip_text = |text: &str| {
+        DIRECTIVE
+            .replace_all(text, |caps: &Captures<'_>| {
+                let playground = PLAYGROUND_DIRECTIVES.contains(&&caps[1]);
+                let strip = if playground {
+                    options.playground
+                } else {
+                    options.directives
+                };
+
+                if strip {
+                    log::debug!("Removing the {} directive", &caps[0]);
+                    String::new()
+                } else {
+                    caps[0].to_string()
+                }
+            })
+            .into_owned()
+    };
+
+    let mut stripped = String::with_capacity(html.len());
+    let mut last = 0;
+    for code in CODE.find_iter(html) {
+        stripped.push_str(&strip_text(&html[last..code.start()]));
+        stripped.push_str(code.as_str());
+        last = code.end();
+    }
+    stripped.push_str(&strip_text(&html[last..]));
+
+    EMPTY_PARAGRAPH.replace_all(&stripped, "").into_owned()
+}
+
+/// Replace links to `print.html` with their text.
+fn strip_print_links(html: &str) -> String {
+    lazy_static! {
+        static ref PRINT_LINK: Regex =
+            Regex::new(r#"(?s)<a\s[^>]*href="(?:\.\./)*print\.html(?:#[^"]*)?"[^>]*>(.*?)</a>"#)
+                .unwrap();
+    }
+
+    PRINT_LINK.replace_all(html, "$1").into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_web_only_constructs() {
+        let src = concat!(
+            "<p>{{#playground example.rs}}</p>\n",
+            "<p>See <a href=\"../print.html\">the whole book</a>.</p>\n",
+            "<p>Literally {{#include file.rs}} and {{#title Intro}}</p>\n",
+        );
+        let should_be = concat!(
+            "<p>See the whole book.</p>\n",
+            "<p>Literally {{#include file.rs}} and {{#title Intro}}</p>\n",
+        );
+
+        assert_eq!(strip(src, &WebOnly::default()), should_be);
+    }
+
+    #[test]
+    fn directives_can_be_stripped() {
+        let src = "<p>{{#include file.rs}}</p>\n<p>Hello {{#title Intro}}</p>\n";
+        let options = WebOnly {
+            directives: true,
+            ..WebOnly::default()
+        };
+
+        assert_eq!(strip(src, &options), "<p>Hello </p>\n");
+    }
+
+    #[test]
+    fn directives_in_code_are_examples() {
+        let src = concat!(
+            "<p>Use <code>{{#playground example.rs}}</code> to embed it.</p>\n",
+            "<pre><code>{{#include file.rs}}\n{{#playpen example.rs}}\n</code></pre>\n",
+        );
+        let options = WebOnly {
+            directives: true,
+            ..WebOnly::default()
+        };
+
+        assert_eq!(strip(src, &options), src);
+    }
+
+    #[test]
+    fn constructs_can_be_kept() {
+        let src = "<p>{{#playpen example.rs}} <a href=\"print.html\">Print</a></p>";
+        let options = WebOnly {
+            print_links: false,
+            playground: false,
+            ..WebOnly::default()
+        };
+
+        assert_eq!(strip(src, &options), src);
+    }
+}
@@ -2,12 +2,17 @@ extern crate epub;
 extern crate failure;
 extern crate mdbook;
 extern crate mdbook_epub;
+extern crate serde_json;
 extern crate tempdir;
+extern crate zip;
 
 use epub::doc::EpubDoc;
 use failure::{Error, SyncFailure};
+use mdbook::book::BookItem;
 use mdbook::renderer::RenderContext;
 use mdbook::MDBook;
+use std::collections::HashMap;
+use std::fs;
 use std::path::Path;
 use tempdir::TempDir;
 
@@ -56,6 +61,53 @@ fn look_for_chapter_1_heading() {
     assert!(content.contains("<h1>Chapter 1</h1>"));
 }
 
+#[test]
+fn chapters_link_to_the_stylesheet() {
+    let mut doc = generate_epub().unwrap();
+
+    let path = Path::new("OEBPS").join("chapter_1.html");
+    let path = path.display().to_string();
+    let content = doc.get_resource_str_by_path(path).unwrap();
+
+    assert!(content.contains(r#"href="stylesheet.css""#));
+}
+
+#[test]
+fn stylesheets_point_at_embedded_fonts() {
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    let font = temp.path().join("MyFont.ttf");
+    let css = temp.path().join("custom.css");
+    fs::write(&font, b"\x00\x01\x00\x00").unwrap();
+    fs::write(&css, "@font-face { src: url(\"MyFont.ttf\"); }\n").unwrap();
+    ctx.config
+        .set(
+            "output.epub.additional-fonts",
+            vec![font.display().to_string()],
+        )
+        .unwrap();
+    ctx.config
+        .set(
+            "output.epub.additional-css",
+            vec![css.display().to_string()],
+        )
+        .unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file.display().to_string()).unwrap();
+    let stylesheet = doc
+        .get_resource_str_by_path("OEBPS/styles/custom.css")
+        .unwrap();
+
+    // the stylesheet lives in OEBPS/styles/
+    assert!(
+        stylesheet.contains(r#"url("../fonts/MyFont.ttf")"#),
+        "{}",
+        stylesheet
+    );
+    assert!(doc.get_resource_by_path("OEBPS/fonts/MyFont.ttf").is_ok());
+}
+
 #[test]
 fn rendered_document_contains_all_chapter_files_and_assets() {
     let chapters = vec!["chapter_1.html", "rust-logo.png"];
@@ -70,6 +122,296 @@ fn rendered_document_contains_all_chapter_files_and_assets() {
     }
 }
 
+#[test]
+fn checksums_are_written_next_to_the_book() {
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("output.epub.checksums", true).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let sidecar = output_file.with_extension("epub.sha256");
+    let checksums = fs::read_to_string(sidecar).unwrap();
+
+    assert!(checksums.contains("  OEBPS/chapter_1.html\n"));
+    assert!(checksums.contains("  OEBPS/rust-logo.png\n"));
+}
+
+#[test]
+fn dry_run_finds_chapters_and_assets_without_writing_anything() {
+    let (ctx, _md, temp) = create_dummy_book().unwrap();
+
+    let report = mdbook_epub::dry_run(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    assert!(!output_file.exists());
+    assert_eq!(report.chapters.len(), 1);
+    assert_eq!(report.assets.len(), 1);
+    assert!(report.broken_links.is_empty());
+}
+
+#[test]
+fn check_reports_every_problem_with_the_chapters() {
+    let (mut ctx, _md, _temp) = create_dummy_book().unwrap();
+    for item in ctx.book.sections.iter_mut() {
+        if let BookItem::Chapter(ref mut ch) = *item {
+            ch.content
+                .push_str("\n![one](missing-1.png) ![two](missing-2.png)\n\n<p>Hello<br></p>\n");
+        }
+    }
+
+    let report = mdbook_epub::check(&ctx);
+
+    let errors: Vec<&str> = report
+        .problems
+        .iter()
+        .filter(|problem| problem.severity == mdbook_epub::Severity::Error)
+        .map(|problem| problem.message.as_str())
+        .collect();
+    assert!(
+        errors.iter().any(|e| e.contains("missing-1.png")),
+        "{:?}",
+        errors
+    );
+    assert!(
+        errors.iter().any(|e| e.contains("missing-2.png")),
+        "{:?}",
+        errors
+    );
+    assert!(errors
+        .iter()
+        .any(|e| e.starts_with("chapter_1.md isn't valid XHTML")));
+}
+
+#[test]
+fn rebuilding_an_unchanged_book_gives_the_same_file() {
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config
+        .set("output.epub.modified", "2019-05-01T00:00:00Z")
+        .unwrap();
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+
+    mdbook_epub::generate(&ctx).unwrap();
+    let first = fs::read(&output_file).unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+    let second = fs::read(&output_file).unwrap();
+
+    assert!(first == second);
+}
+
+#[test]
+fn additional_resources_never_replace_files_already_in_the_book() {
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    for dir in &["a", "b"] {
+        fs::create_dir(temp.path().join(dir)).unwrap();
+        fs::write(temp.path().join(dir).join("notes.txt"), dir).unwrap();
+    }
+    fs::write(temp.path().join("stylesheet.css"), "body {}").unwrap();
+    let resource = |src: &str, dest: Option<&str>| {
+        let mut resource = HashMap::new();
+        resource.insert("src", temp.path().join(src).display().to_string());
+        if let Some(dest) = dest {
+            resource.insert("dest", dest.to_string());
+        }
+        resource
+    };
+    let logo = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dummy/src/rust-logo.png");
+    let resources = vec![
+        resource("a/*.txt", Some("extra")),
+        resource("b/*.txt", Some("extra")),
+        resource("stylesheet.css", None),
+        // already embedded because chapter 1 links to it
+        resource(&logo.display().to_string(), None),
+    ];
+    ctx.config
+        .set("output.epub.additional-resources", resources)
+        .unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut archive = zip::ZipArchive::new(fs::File::open(&output_file).unwrap()).unwrap();
+    let mut names = Vec::new();
+    for i in 0..archive.len() {
+        names.push(archive.by_index(i).unwrap().name().to_string());
+    }
+    let mut unique = names.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(names.len(), unique.len(), "{:?}", names);
+
+    let mut doc = EpubDoc::new(&output_file.display().to_string()).unwrap();
+    let notes = doc
+        .get_resource_str_by_path("OEBPS/extra/notes.txt")
+        .unwrap();
+    assert_eq!(notes, "a");
+    let stylesheet = doc
+        .get_resource_str_by_path("OEBPS/stylesheet.css")
+        .unwrap();
+    assert_ne!(stylesheet, "body {}");
+}
+
+#[test]
+fn additional_resources_never_replace_an_empty_default_stylesheet() {
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    fs::write(temp.path().join("stylesheet.css"), "body {}").unwrap();
+    let mut resource = HashMap::new();
+    resource.insert(
+        "src",
+        temp.path().join("stylesheet.css").display().to_string(),
+    );
+    ctx.config
+        .set("output.epub.use-default-css", false)
+        .unwrap();
+    ctx.config
+        .set("output.epub.additional-resources", vec![resource])
+        .unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut archive = zip::ZipArchive::new(fs::File::open(&output_file).unwrap()).unwrap();
+    let stylesheets = (0..archive.len())
+        .filter(|&i| archive.by_index(i).unwrap().name() == "OEBPS/stylesheet.css")
+        .count();
+    assert_eq!(stylesheets, 1);
+}
+
+#[test]
+fn every_phase_of_the_build_is_timed() {
+    let (ctx, _md, _temp) = create_dummy_book().unwrap();
+
+    let got = mdbook_epub::generate_with_stats(&ctx).unwrap();
+
+    assert_eq!(got.len(), 1);
+    let phases: Vec<&str> = got[0].1.phases.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(
+        phases,
+        vec![
+            "prepare",
+            "render chapters",
+            "embed styles",
+            "embed assets",
+            "write zip"
+        ]
+    );
+}
+
+#[test]
+fn dedication_and_acknowledgments_get_their_own_pages() {
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config
+        .set("output.epub.dedication", "For *Ferris*")
+        .unwrap();
+    ctx.config
+        .set("output.epub.acknowledgments", "Thanks to everyone.")
+        .unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file.display().to_string()).unwrap();
+    let dedication = doc
+        .get_resource_str_by_path("OEBPS/dedication.xhtml")
+        .unwrap();
+    let acknowledgments = doc
+        .get_resource_str_by_path("OEBPS/acknowledgments.xhtml")
+        .unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+
+    assert!(dedication.contains("epub:type=\"dedication\""));
+    assert!(dedication.contains("<p>For <em>Ferris</em></p>"));
+    assert!(acknowledgments.contains("<h1>Acknowledgments</h1>"));
+    assert!(acknowledgments.contains("<p>Thanks to everyone.</p>"));
+    assert!(opf.contains("type=\"dedication\""));
+    assert!(opf.contains("type=\"acknowledgements\""));
+
+    // both go in front of the chapters
+    let spine = |page: &str| doc.spine.iter().position(|id| id == page).unwrap();
+    assert!(spine("dedication_xhtml") < spine("acknowledgments_xhtml"));
+    assert!(spine("acknowledgments_xhtml") < spine("chapter_1_html"));
+}
+
+#[test]
+fn generated_text_follows_the_book_language() {
+    let (mut ctx, _md, temp) = create_dummy_book().unwrap();
+    ctx.config.set("book.language", "de").unwrap();
+    ctx.config
+        .set(
+            "output.epub.spine",
+            vec!["title-page", "toc", "acknowledgments", "chapters"],
+        )
+        .unwrap();
+    ctx.config
+        .set("output.epub.acknowledgments", "Danke!")
+        .unwrap();
+    mdbook_epub::generate(&ctx).unwrap();
+
+    let output_file = mdbook_epub::output_filename(temp.path(), &ctx.config);
+    let mut doc = EpubDoc::new(&output_file.display().to_string()).unwrap();
+    let ncx = doc.get_resource_str_by_path("OEBPS/toc.ncx").unwrap();
+    let opf = doc.get_resource_str_by_path("OEBPS/content.opf").unwrap();
+    let nav = doc.get_resource_str_by_path("OEBPS/nav.xhtml").unwrap();
+    let toc = doc.get_resource_str_by_path("OEBPS/toc.xhtml").unwrap();
+    let acknowledgments = doc
+        .get_resource_str_by_path("OEBPS/acknowledgments.xhtml")
+        .unwrap();
+
+    assert!(ncx.contains("<text>Inhaltsverzeichnis</text>"));
+    assert!(opf.contains("title=\"Inhaltsverzeichnis\""));
+    assert!(nav.contains(">Inhaltsverzeichnis</h1>"));
+    assert!(toc.contains("Inhaltsverzeichnis"));
+    for file in &[&ncx, &opf, &nav, &toc] {
+        assert!(!file.to_lowercase().contains("table of contents"));
+    }
+    assert!(acknowledgments.contains("<h1>Danksagung</h1>"));
+
+    // generated pages are named in the guide and landmarks, but not the TOC
+    assert!(opf.contains("title=\"Titelseite\" href=\"titlepage.xhtml\""));
+    assert!(opf.contains("title=\"Danksagung\" href=\"acknowledgments.xhtml\""));
+    assert!(!opf.contains("title=\"\""));
+    assert!(nav.contains("href=\"titlepage.xhtml\">Titelseite</a>"));
+    assert!(nav.contains("href=\"acknowledgments.xhtml\">Danksagung</a>"));
+    assert!(!ncx.contains("Titelseite"));
+}
+
+/// A serialized `RenderContext` for the dummy book, as a newer `mdbook` which
+/// knows about part titles would send it.
+fn render_context_from_newer_mdbook(ctx: &RenderContext) -> Vec<u8> {
+    let mut value = serde_json::to_value(ctx).unwrap();
+    value["version"] = serde_json::Value::from("99.0.0");
+    value["book"]["sections"]
+        .as_array_mut()
+        .unwrap()
+        .insert(0, serde_json::json!({ "PartTitle": "Part 1" }));
+
+    serde_json::to_vec(&value).unwrap()
+}
+
+#[test]
+fn unreadable_context_from_another_mdbook_version_suggests_forcing() {
+    let (ctx, _md, _temp) = create_dummy_book().unwrap();
+    let json = render_context_from_newer_mdbook(&ctx);
+
+    let got = mdbook_epub::load_render_context(json.as_slice(), false).unwrap_err();
+
+    let message = got.to_string();
+    assert!(
+        message.contains("Incompatible mdbook version"),
+        "{}",
+        message
+    );
+    assert!(message.contains("got 99.0.0"), "{}", message);
+    assert!(message.contains("--force"), "{}", message);
+}
+
+#[test]
+fn forcing_skips_book_items_this_mdbook_cant_read() {
+    let (ctx, _md, _temp) = create_dummy_book().unwrap();
+    let json = render_context_from_newer_mdbook(&ctx);
+
+    let got = mdbook_epub::load_render_context(json.as_slice(), true).unwrap();
+
+    assert_eq!(got.version, "99.0.0");
+    assert_eq!(got.book.sections, ctx.book.sections);
+}
+
 /// Use `MDBook::load()` to load the dummy book into memory, then set up the
 /// `RenderContext` for use the EPUB generator.
 fn create_dummy_book() -> Result<(RenderContext, MDBook, TempDir), Error> {